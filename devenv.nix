@@ -11,7 +11,9 @@
     pkgs.gst_all_1.gst-plugins-base
     pkgs.gst_all_1.gst-plugins-good
     pkgs.gst_all_1.gst-plugins-bad
+    pkgs.gst_all_1.gst-plugins-ugly
     pkgs.gst_all_1.gst-libav
+    pkgs.gst_all_1.gst-rtsp-server
   ];
 
   # https://devenv.sh/languages/
@@ -0,0 +1,160 @@
+use crate::inference::Detection;
+use image::{DynamicImage, Rgb, RgbImage};
+
+/// Width of the box outlines, in pixels.
+const LINE_WIDTH: u32 = 2;
+/// Glyphs are drawn this many times larger than the 5×7 font.
+const TEXT_SCALE: u32 = 2;
+
+const PALETTE: &[[u8; 3]] = &[
+    [230, 25, 75],
+    [60, 180, 75],
+    [255, 225, 25],
+    [0, 130, 200],
+    [245, 130, 48],
+    [145, 30, 180],
+    [70, 240, 240],
+    [240, 50, 230],
+];
+
+/// A copy of `image` with the box, label, confidence and track ID of every
+/// detection drawn onto it.
+pub fn annotate(image: &DynamicImage, detections: &[Detection]) -> RgbImage {
+    let mut image = image.to_rgb8();
+
+    for detection in detections {
+        let color = Rgb(label_color(&detection.label));
+        let bounding_box = &detection.bounding_box;
+        let (x1, y1) = (
+            bounding_box.x1.max(0.0) as u32,
+            bounding_box.y1.max(0.0) as u32,
+        );
+        let (x2, y2) = (
+            bounding_box.x2.max(0.0) as u32,
+            bounding_box.y2.max(0.0) as u32,
+        );
+
+        fill(&mut image, x1, y1, x2, y1 + LINE_WIDTH, color);
+        fill(&mut image, x1, y2.saturating_sub(LINE_WIDTH), x2, y2, color);
+        fill(&mut image, x1, y1, x1 + LINE_WIDTH, y2, color);
+        fill(&mut image, x2.saturating_sub(LINE_WIDTH), y1, x2, y2, color);
+
+        let mut text = format!("{} {:.2}", detection.label, detection.confidence);
+        if let Some(track_id) = detection.track_id {
+            text.push_str(&format!(" #{track_id}"));
+        }
+        let text_height = 9 * TEXT_SCALE;
+        let text_width = 6 * TEXT_SCALE * text.chars().count() as u32 + TEXT_SCALE;
+        // Above the box, or inside it when there is no room
+        let text_y = if y1 >= text_height {
+            y1 - text_height
+        } else {
+            y1
+        };
+        fill(
+            &mut image,
+            x1,
+            text_y,
+            x1 + text_width,
+            text_y + text_height,
+            color,
+        );
+        draw_text(
+            &mut image,
+            x1 + TEXT_SCALE,
+            text_y + TEXT_SCALE,
+            &text,
+            Rgb([0, 0, 0]),
+        );
+    }
+
+    image
+}
+
+/// A stable color per label.
+fn label_color(label: &str) -> [u8; 3] {
+    let hash = label.bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+
+    PALETTE[hash % PALETTE.len()]
+}
+
+/// Fill `x1..x2` × `y1..y2`, clipped to the image.
+fn fill(image: &mut RgbImage, x1: u32, y1: u32, x2: u32, y2: u32, color: Rgb<u8>) {
+    for y in y1..y2.min(image.height()) {
+        for x in x1..x2.min(image.width()) {
+            image.put_pixel(x, y, color);
+        }
+    }
+}
+
+fn draw_text(image: &mut RgbImage, x: u32, y: u32, text: &str, color: Rgb<u8>) {
+    for (index, character) in text.chars().enumerate() {
+        let glyph_x = x + index as u32 * 6 * TEXT_SCALE;
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for column in 0..5 {
+                if bits & (0x10 >> column) != 0 {
+                    let pixel_x = glyph_x + column * TEXT_SCALE;
+                    let pixel_y = y + row as u32 * TEXT_SCALE;
+                    fill(
+                        image,
+                        pixel_x,
+                        pixel_y,
+                        pixel_x + TEXT_SCALE,
+                        pixel_y + TEXT_SCALE,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a 5×7 glyph, the leftmost pixel in the 5th bit. Letters are
+/// drawn in capitals; unknown characters are left blank.
+fn glyph(character: char) -> [u8; 7] {
+    match character.to_ascii_uppercase() {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        _ => [0x00; 7],
+    }
+}
@@ -0,0 +1,414 @@
+use crate::annotate;
+use crate::camera::Cameras;
+use crate::event::DetectionEvent;
+use crate::inference::Detection;
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// Largest request body accepted, far more than any camera needs.
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Serve the control and status API on `http://<address>/`.
+    pub enabled: bool,
+    pub address: String,
+    /// How many detection events `GET /detections` can return.
+    pub recent_detections: usize,
+    /// Required of every request as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Let `POST /cameras` read local files and directories, test patterns
+    /// and `pipeline:` descriptions, not only RTSP cameras.
+    pub allow_local_sources: bool,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:8080".to_string(),
+            recent_detections: 100,
+            token: None,
+            allow_local_sources: false,
+        }
+    }
+}
+
+/// The latest frame of every camera and the last detection events, as kept
+/// by [`RecentSink`].
+pub struct Recent {
+    snapshots: Mutex<HashMap<String, Arc<Snapshot>>>,
+    detections: Mutex<VecDeque<DetectionEvent>>,
+    capacity: usize,
+}
+
+impl Recent {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            snapshots: Mutex::default(),
+            detections: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        })
+    }
+
+    fn snapshot(&self, camera_id: &str) -> Option<Arc<Snapshot>> {
+        self.snapshots.lock().unwrap().get(camera_id).cloned()
+    }
+
+    /// The last `limit` detection events, newest first.
+    fn detections(&self, camera_id: Option<&str>, limit: usize) -> Vec<DetectionEvent> {
+        self.detections
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|event| camera_id.is_none_or(|camera_id| event.camera_id == camera_id))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// A frame with its detections, only annotated when asked for.
+struct Snapshot {
+    image: DynamicImage,
+    detections: Vec<Detection>,
+}
+
+/// Feeds [`Recent`] from the inferences, like any other sink.
+pub struct RecentSink {
+    recent: Arc<Recent>,
+}
+
+impl RecentSink {
+    pub fn new(recent: Arc<Recent>) -> Self {
+        Self { recent }
+    }
+}
+
+impl DetectionSink for RecentSink {
+    fn name(&self) -> &'static str {
+        "api"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        let snapshot = Snapshot {
+            image: inference.frame.image.clone(),
+            detections: inference.detections.clone(),
+        };
+        self.recent
+            .snapshots
+            .lock()
+            .unwrap()
+            .insert(inference.frame.camera_id.to_string(), Arc::new(snapshot));
+
+        if !inference.detections.is_empty() {
+            let mut detections = self.recent.detections.lock().unwrap();
+            if detections.len() >= self.recent.capacity {
+                detections.pop_front();
+            }
+            detections.push_back(inference.event.clone());
+        }
+
+        Ok(())
+    }
+}
+
+/// Body of `POST /cameras`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewCamera {
+    id: String,
+    /// Anything `source::from_argument` understands.
+    source: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+type Response = tiny_http::Response<Cursor<Vec<u8>>>;
+
+/// Serves the control and status API on its own thread until
+/// [`ApiServer::stop`], to the bearer of the configured token if any:
+///
+/// - `GET /cameras`: every camera with its state
+/// - `POST /cameras`: start a camera from `{"id": …, "source": …}`; only
+///   RTSP sources unless `allow_local_sources`
+/// - `DELETE /cameras/<id>`: stop and forget a camera
+/// - `POST /cameras/<id>/restart`: rebuild a camera's pipeline
+/// - `GET /cameras/<id>/snapshot.jpg`: its last inferred frame, annotated
+/// - `GET /detections?camera=<id>&limit=<n>`: the last detection events
+pub struct ApiServer {
+    server: Arc<tiny_http::Server>,
+    thread: JoinHandle<()>,
+}
+
+impl ApiServer {
+    pub fn start(
+        config: &ApiConfig,
+        cameras: Arc<Cameras>,
+        recent: Arc<Recent>,
+    ) -> anyhow::Result<Self> {
+        let server = tiny_http::Server::http(&config.address)
+            .map_err(|err| anyhow::anyhow!("{err}"))
+            .with_context(|| format!("failed to listen on {}", config.address))?;
+        let server = Arc::new(server);
+        tracing::info!("Serving the API on http://{}", config.address);
+
+        let thread = std::thread::Builder::new()
+            .name("api".to_string())
+            .spawn({
+                let server = server.clone();
+                let config = config.clone();
+                move || {
+                    for mut request in server.incoming_requests() {
+                        let response = if is_authorized(&request, &config) {
+                            route(&mut request, &config, &cameras, &recent)
+                        } else {
+                            unauthorized()
+                        };
+                        if let Err(err) = request.respond(response) {
+                            tracing::debug!("Failed to answer an API request: {}", err);
+                        }
+                    }
+                }
+            })
+            .context("failed to spawn API thread")?;
+
+        Ok(Self { server, thread })
+    }
+
+    pub fn stop(self) {
+        self.server.unblock();
+        if self.thread.join().is_err() {
+            tracing::error!("API server panicked");
+        }
+    }
+}
+
+/// Whether the request bears the configured token, if any.
+fn is_authorized(request: &tiny_http::Request, config: &ApiConfig) -> bool {
+    let Some(token) = &config.token else {
+        return true;
+    };
+
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .any(|bearer| constant_time_eq(bearer.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Compare without returning at the first difference, so that the time
+/// taken does not tell how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Whether `POST /cameras` may start a camera from `source`: RTSP cameras
+/// only, unless local sources are allowed.
+fn is_allowed_source(source: &str, config: &ApiConfig) -> bool {
+    config.allow_local_sources || source.starts_with("rtsp://") || source.starts_with("rtsps://")
+}
+
+fn route(
+    request: &mut tiny_http::Request,
+    config: &ApiConfig,
+    cameras: &Arc<Cameras>,
+    recent: &Recent,
+) -> Response {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    match (&method, segments.as_slice()) {
+        (tiny_http::Method::Get, ["cameras"]) => json(200, &cameras.list()),
+        (tiny_http::Method::Post, ["cameras"]) => {
+            let mut body = String::new();
+            let read = request
+                .as_reader()
+                .take(MAX_BODY_SIZE + 1)
+                .read_to_string(&mut body);
+            if let Err(err) = read {
+                return error(400, format!("failed to read body: {err}"));
+            }
+            if body.len() as u64 > MAX_BODY_SIZE {
+                return error(413, format!("body is larger than {MAX_BODY_SIZE} bytes"));
+            }
+            let camera = match serde_json::from_str::<NewCamera>(&body) {
+                Ok(camera) => camera,
+                Err(err) => return error(400, format!("invalid camera: {err}")),
+            };
+            if !is_allowed_source(&camera.source, config) {
+                return error(
+                    403,
+                    "only rtsp:// and rtsps:// sources may be added unless \
+                     api.allow_local_sources is set"
+                        .to_string(),
+                );
+            }
+
+            match cameras.add(&camera.id, &camera.source) {
+                Ok(()) => {
+                    tracing::info!("Added camera {}", camera.id);
+                    let added = cameras.list().into_iter().find(|info| info.id == camera.id);
+                    json(201, &added)
+                }
+                Err(err) => error(400, format!("{err:#}")),
+            }
+        }
+        (tiny_http::Method::Delete, ["cameras", id]) => {
+            if cameras.remove(id) {
+                empty(204)
+            } else {
+                error(404, format!("no camera {id}"))
+            }
+        }
+        (tiny_http::Method::Post, ["cameras", id, "restart"]) => match cameras.restart(id) {
+            Ok(true) => empty(202),
+            Ok(false) => error(404, format!("no camera {id}")),
+            Err(err) => error(500, format!("{err:#}")),
+        },
+        (tiny_http::Method::Get, ["cameras", id, "snapshot.jpg"]) => {
+            let Some(snapshot) = recent.snapshot(id) else {
+                return error(404, format!("no snapshot of {id} yet"));
+            };
+            let image = annotate::annotate(&snapshot.image, &snapshot.detections);
+            let mut jpeg = Cursor::new(Vec::new());
+            if let Err(err) = image.write_to(&mut jpeg, ImageFormat::Jpeg) {
+                return error(500, format!("failed to encode snapshot: {err}"));
+            }
+
+            with_content_type(Response::from_data(jpeg.into_inner()), "image/jpeg")
+        }
+        (tiny_http::Method::Get, ["detections"]) => {
+            let mut camera_id = None;
+            let mut limit = recent.capacity;
+            for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                let Some(value) = decode_query_value(value) else {
+                    return error(400, format!("invalid query value `{value}`"));
+                };
+                match key {
+                    "camera" => camera_id = Some(value),
+                    "limit" => match value.parse() {
+                        Ok(value) => limit = value,
+                        Err(_) => return error(400, format!("invalid limit `{value}`")),
+                    },
+                    _ => (),
+                }
+            }
+
+            json(200, &recent.detections(camera_id.as_deref(), limit))
+        }
+        _ => error(404, format!("no route for {method} {path}")),
+    }
+}
+
+/// Decode a percent-encoded query value, where `+` stands for a space.
+fn decode_query_value(value: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let digits = [bytes.next()?, bytes.next()?];
+                let digits = std::str::from_utf8(&digits).ok()?;
+                if !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(u8::from_str_radix(digits, 16).ok()?);
+            }
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn json(status: u16, body: &impl Serialize) -> Response {
+    let body = serde_json::to_vec(body).expect("API responses serialize");
+
+    with_content_type(Response::from_data(body), "application/json").with_status_code(status)
+}
+
+fn error(status: u16, message: String) -> Response {
+    json(status, &ErrorBody { error: message })
+}
+
+fn unauthorized() -> Response {
+    error(401, "missing or wrong bearer token".to_string()).with_header(
+        tiny_http::Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+            .expect("valid header"),
+    )
+}
+
+fn empty(status: u16) -> Response {
+    Response::from_data(Vec::new()).with_status_code(status)
+}
+
+fn with_content_type(response: Response, content_type: &str) -> Response {
+    response.with_header(
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+            .expect("valid header"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_query_values() {
+        assert_eq!(
+            decode_query_value("front-door").as_deref(),
+            Some("front-door")
+        );
+        assert_eq!(
+            decode_query_value("front%2Ddoor").as_deref(),
+            Some("front-door")
+        );
+        assert_eq!(decode_query_value("a+b%20c").as_deref(), Some("a b c"));
+        assert_eq!(decode_query_value("caf%C3%A9").as_deref(), Some("café"));
+    }
+
+    #[test]
+    fn rejects_malformed_query_values() {
+        assert_eq!(decode_query_value("100%"), None);
+        assert_eq!(decode_query_value("%2"), None);
+        assert_eq!(decode_query_value("%+1"), None);
+        assert_eq!(decode_query_value("%zz"), None);
+        // Not UTF-8
+        assert_eq!(decode_query_value("%ff"), None);
+    }
+
+    #[test]
+    fn adds_only_rtsp_cameras_by_default() {
+        let mut config = ApiConfig::default();
+        assert!(is_allowed_source("rtsp://camera/stream", &config));
+        assert!(is_allowed_source("rtsps://camera/stream", &config));
+        assert!(!is_allowed_source("/etc/passwd", &config));
+        assert!(!is_allowed_source(
+            "pipeline:filesrc location=/etc/passwd",
+            &config
+        ));
+        assert!(!is_allowed_source("test:", &config));
+
+        config.allow_local_sources = true;
+        assert!(is_allowed_source("pipeline:videotestsrc", &config));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::ModelVersion;
+use crate::inference::{DetectionFilter, Detector};
+use image::{DynamicImage, Rgb, RgbImage};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use yolo_rs::{image_to_yolo_input_tensor, inference};
+
+/// Time preprocessing and inference of `model_path` on a noise frame.
+pub fn run(
+    model_path: &Path,
+    version: ModelVersion,
+    width: u32,
+    height: u32,
+    warmup: usize,
+    iterations: usize,
+) -> anyhow::Result<()> {
+    let detector = Detector::load(model_path, version, DetectionFilter::default())?;
+    let frame = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+        Rgb([fastrand::u8(..), fastrand::u8(..), fastrand::u8(..)])
+    }));
+
+    println!(
+        "Benchmarking {} on {}x{} frames ({} warmup, {} measured iterations)",
+        model_path.display(),
+        width,
+        height,
+        warmup,
+        iterations
+    );
+
+    for _ in 0..warmup {
+        detector.detect(&frame)?;
+    }
+
+    let mut preprocess = Vec::with_capacity(iterations);
+    let mut infer = Vec::with_capacity(iterations);
+    let started_at = Instant::now();
+
+    for _ in 0..iterations {
+        let now = Instant::now();
+        let yolo_input = image_to_yolo_input_tensor(&frame);
+        preprocess.push(now.elapsed());
+
+        let now = Instant::now();
+        inference(detector.model(), yolo_input.view())?;
+        infer.push(now.elapsed());
+    }
+
+    let total = started_at.elapsed();
+
+    print_stats("preprocess", &mut preprocess);
+    print_stats("inference", &mut infer);
+    println!(
+        "throughput: {:.2} frames/s",
+        iterations as f64 / total.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn print_stats(name: &str, samples: &mut [Duration]) {
+    samples.sort();
+
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+
+    println!(
+        "{name:>10}: mean {:?}, p50 {:?}, p95 {:?}, min {:?}, max {:?}",
+        mean,
+        percentile(0.50),
+        percentile(0.95),
+        samples[0],
+        samples[samples.len() - 1]
+    );
+}
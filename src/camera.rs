@@ -0,0 +1,361 @@
+use crate::config::{CameraConfig, Config};
+use crate::inference::{Analysis, Frame};
+use crate::scheduler::FairQueue;
+use crate::sink::Dispatcher;
+use crate::source::{self, FrameSource};
+use crate::supervisor::{CameraState, Control, Status};
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+
+/// A frame source tagged with the ID used in logs and outputs.
+pub struct Camera {
+    pub id: Arc<str>,
+    pub source: Box<dyn FrameSource>,
+}
+
+impl Camera {
+    pub fn from_config(config: &CameraConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: config.id.as_str().into(),
+            source: source::from_argument(&config.source)?,
+        })
+    }
+}
+
+/// The cameras being read, each on its own thread, which may be added,
+/// removed and restarted while running.
+pub struct Cameras {
+    config: Arc<Config>,
+    inference_queue: Arc<FairQueue<Frame>>,
+    analysis: Arc<Analysis>,
+    /// Weak, so that the sinks can be closed while the signal handler still
+    /// holds on to the cameras.
+    sinks: Weak<Dispatcher>,
+    running: Mutex<BTreeMap<Arc<str>, Running>>,
+    /// Notified whenever a camera thread ends.
+    ended: Condvar,
+    shutting_down: AtomicBool,
+    /// Why the run was aborted, if it was.
+    failure: Mutex<Option<String>>,
+}
+
+struct Running {
+    /// The `source` it was started from, to restart it.
+    source: String,
+    description: String,
+    control: Control,
+    thread: JoinHandle<()>,
+}
+
+/// A camera as listed by [`Cameras::list`].
+#[derive(Debug, Serialize)]
+pub struct CameraInfo {
+    pub id: String,
+    pub source: String,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+impl Cameras {
+    pub fn new(
+        config: Arc<Config>,
+        inference_queue: Arc<FairQueue<Frame>>,
+        analysis: Arc<Analysis>,
+        sinks: &Arc<Dispatcher>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            inference_queue,
+            analysis,
+            sinks: Arc::downgrade(sinks),
+            running: Mutex::default(),
+            ended: Condvar::new(),
+            shutting_down: AtomicBool::new(false),
+            failure: Mutex::default(),
+        })
+    }
+
+    /// Start reading a camera that is not in the configuration file. Its
+    /// detections go through the same sinks and clips, but it has no zones
+    /// or lines; its RTSP stream is mounted with its first inferred frame.
+    ///
+    /// The IDs of the configured cameras are taken, even once removed, as
+    /// their zones and lines are found by ID.
+    pub fn add(self: &Arc<Self>, id: &str, source: &str) -> anyhow::Result<()> {
+        if !is_valid_id(id) {
+            bail!("camera ID `{id}` must match [A-Za-z0-9_-]+");
+        }
+        if self.config.cameras.iter().any(|camera| camera.id == id) {
+            bail!("camera ID `{id}` is taken by the configuration file");
+        }
+        let camera = Camera {
+            id: id.into(),
+            source: source::from_argument(source)?,
+        };
+
+        self.start(camera, source.to_string())
+    }
+
+    /// Start reading `camera` on its own thread; `source` is what it was
+    /// created from.
+    pub fn start(self: &Arc<Self>, camera: Camera, source: String) -> anyhow::Result<()> {
+        let camera_id = camera.id.clone();
+        let mut running = self.running.lock().unwrap();
+        if self.is_shutting_down() {
+            bail!("shutting down");
+        }
+        if running.contains_key(&camera_id) {
+            bail!("camera {camera_id} already exists");
+        }
+
+        let description = camera.source.describe();
+        let control = Control::default();
+        let thread = std::thread::Builder::new()
+            .name(format!("camera-{}", camera.id))
+            .spawn({
+                let cameras = self.clone();
+                let control = control.clone();
+                move || {
+                    let recorder = cameras.analysis.recorders.get(&camera.id);
+                    let result = crate::run_camera(
+                        &cameras.config,
+                        &camera,
+                        cameras.inference_queue.clone(),
+                        recorder,
+                        &control,
+                    );
+
+                    let _running = cameras.running.lock().unwrap();
+                    match result {
+                        Ok(()) => control.set_state(CameraState::Stopped),
+                        Err(err) => {
+                            tracing::error!("Camera {} failed: {:?}", camera.id, err);
+                            control.fail(format!("{err:#}"));
+                        }
+                    }
+                    cameras.ended.notify_all();
+                }
+            })?;
+
+        running.insert(
+            camera_id,
+            Running {
+                source,
+                description,
+                control,
+                thread,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<CameraInfo> {
+        self.running
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, running)| CameraInfo {
+                id: id.to_string(),
+                source: running.description.clone(),
+                status: running.control.status(),
+            })
+            .collect()
+    }
+
+    /// Stop a camera and forget it, ending its tracks; returns whether it
+    /// existed.
+    pub fn remove(&self, id: &str) -> bool {
+        let Some(running) = self.running.lock().unwrap().remove(id) else {
+            return false;
+        };
+
+        tracing::info!("Removing camera {}", id);
+        running.stop();
+        self.forget(id);
+        true
+    }
+
+    /// Stop a camera and start it again from the same source, with new
+    /// tracks; returns whether it existed.
+    pub fn restart(self: &Arc<Self>, id: &str) -> anyhow::Result<bool> {
+        let Some(running) = self.running.lock().unwrap().remove(id) else {
+            return Ok(false);
+        };
+
+        tracing::info!("Restarting camera {}", id);
+        let source = running.source.clone();
+        running.stop();
+        self.forget(id);
+        let camera = match self.config.cameras.iter().find(|camera| camera.id == id) {
+            Some(camera) => Camera::from_config(camera)?,
+            None => Camera {
+                id: id.into(),
+                source: source::from_argument(&source)?,
+            },
+        };
+        self.start(camera, source)?;
+        Ok(true)
+    }
+
+    /// Drop the frames of a stopped camera that are left to infer, once the
+    /// one being inferred is done, then end what is followed of it.
+    fn forget(&self, id: &str) {
+        let discarded = self.inference_queue.remove(id);
+        if discarded > 0 {
+            tracing::info!("Discarded {} frames of {} left to infer", discarded, id);
+        }
+
+        let events = self.analysis.end_camera(&self.config, id);
+        if let Some(sinks) = self.sinks.upgrade() {
+            for event in events {
+                sinks.dispatch_event(event);
+            }
+        }
+    }
+
+    /// Count a frame of `camera_id` that failed inference, and return the
+    /// failures in a row so far.
+    pub fn inference_failed(&self, camera_id: &str, error: &str) -> u32 {
+        let running = self.running.lock().unwrap();
+        let Some(running) = running.get(camera_id) else {
+            return 0;
+        };
+
+        let threshold = self.config.supervision.failure_threshold;
+        let failures = running
+            .control
+            .inference_failed(error.to_string(), threshold);
+        if failures == threshold {
+            tracing::error!(
+                "Camera {} is failing: {} frames in a row could not be inferred",
+                camera_id,
+                failures
+            );
+        }
+
+        failures
+    }
+
+    pub fn inference_succeeded(&self, camera_id: &str) {
+        let running = self.running.lock().unwrap();
+        if let Some(running) = running.get(camera_id)
+            && running.control.inference_succeeded()
+        {
+            tracing::info!("Camera {} recovered", camera_id);
+        }
+    }
+
+    /// Ask every camera to stop without waiting for them, and make
+    /// [`Cameras::wait`] return.
+    pub fn shutdown(&self) {
+        let running = self.running.lock().unwrap();
+        self.shutting_down.store(true, Ordering::Relaxed);
+        for running in running.values() {
+            running.control.stop();
+        }
+        self.ended.notify_all();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
+    /// Give up on every camera because their frames cannot be inferred: shut
+    /// down, discarding their frames so that none stays blocked on the full
+    /// queue, and make the run fail with `error`.
+    pub fn abort(&self, error: String) {
+        {
+            let mut failure = self.failure.lock().unwrap();
+            if failure.is_some() {
+                return;
+            }
+            tracing::error!("Giving up: {}", error);
+            *failure = Some(error);
+        }
+
+        self.shutdown();
+        self.inference_queue.close();
+        self.inference_queue.discard();
+    }
+
+    /// Why the run was aborted, if it was.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    /// Wait until every camera ended, or forever if `keep_running`, e.g. so
+    /// that cameras can still be added, unless shutting down.
+    pub fn wait(&self, keep_running: bool) {
+        let running = self.running.lock().unwrap();
+        let _running = self
+            .ended
+            .wait_while(running, |running| {
+                if self.is_shutting_down() {
+                    return false;
+                }
+
+                keep_running || running.values().any(|running| !running.control.has_ended())
+            })
+            .unwrap();
+    }
+
+    /// Stop every camera and return the IDs of those that failed.
+    pub fn stop(&self) -> Vec<Arc<str>> {
+        let running = std::mem::take(&mut *self.running.lock().unwrap());
+        // Let them all drain at once
+        for running in running.values() {
+            running.control.stop();
+        }
+
+        running
+            .into_iter()
+            .filter_map(|(id, running)| {
+                let control = running.control.clone();
+                running.stop();
+                (control.status().state == CameraState::Error).then_some(id)
+            })
+            .collect()
+    }
+}
+
+impl Running {
+    fn stop(self) {
+        self.control.stop();
+        if self.thread.join().is_err() {
+            tracing::error!("Camera thread panicked");
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Parse a `[<id>=]<SOURCE>` command-line argument.
+    ///
+    /// Cameras without an explicit ID are named `cam<index>`.
+    pub fn from_argument(index: usize, argument: &str) -> Self {
+        let (id, source) = match argument.split_once('=') {
+            Some((id, source)) if is_valid_id(id) => (id.to_string(), source),
+            _ => (format!("cam{index}"), argument),
+        };
+
+        Self {
+            id,
+            source: source.to_string(),
+            zones: Vec::new(),
+            lines: Vec::new(),
+            rtsp_mount_path: None,
+        }
+    }
+}
+
+/// Camera IDs end up in file names and topics, so keep them simple.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
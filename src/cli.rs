@@ -0,0 +1,151 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+const SOURCE_HELP: &str = "\
+SOURCE can be one of:
+  rtsp://...              an RTSP camera
+  <file> | file://<file>  a local video file
+  <directory>             a directory of images
+  test:[pattern]          a videotestsrc test pattern
+  pipeline:<description>  a gst-launch style pipeline producing raw video";
+
+/// Run YOLO object detection on video streams.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Configuration file (TOML or YAML).
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Stream the cameras into the detector.
+    ///
+    /// On SIGINT or SIGTERM, the streams are ended, the queued frames are
+    /// inferred for at most `queue.drain_timeout` and the sinks are flushed;
+    /// a second signal exits right away with status 130. The exit status is
+    /// non-zero if a camera failed, the model could not be loaded or queued
+    /// frames had to be discarded.
+    #[command(after_help = SOURCE_HELP)]
+    Run {
+        /// Cameras to run in addition to those of the configuration file.
+        /// Cameras without an ID are named cam0, cam1, ...
+        #[arg(value_name = "[<ID>=]<SOURCE>")]
+        sources: Vec<String>,
+    },
+
+    /// Detect objects in a single image or video file.
+    DetectFile {
+        /// The image or video to scan.
+        path: PathBuf,
+    },
+
+    /// Measure the inference throughput of a model on synthetic frames.
+    Bench {
+        /// Model to benchmark instead of the configured one.
+        #[arg(long)]
+        model: Option<PathBuf>,
+
+        /// Width of the synthetic frames.
+        #[arg(long, default_value_t = 1920, value_parser = clap::value_parser!(u32).range(1..))]
+        width: u32,
+
+        /// Height of the synthetic frames.
+        #[arg(long, default_value_t = 1080, value_parser = clap::value_parser!(u32).range(1..))]
+        height: u32,
+
+        /// Iterations run before measuring.
+        #[arg(long, default_value_t = 5)]
+        warmup: usize,
+
+        /// Iterations measured.
+        #[arg(
+            long,
+            default_value_t = 50,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        iterations: usize,
+    },
+
+    /// Connect to a source and print its negotiated caps, codec, resolution
+    /// and frame rate without running inference.
+    #[command(after_help = SOURCE_HELP)]
+    Probe {
+        source: String,
+
+        /// Give up when no frame arrived within this duration.
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+
+        /// Number of frames used to measure the frame rate.
+        #[arg(
+            long,
+            default_value_t = 30,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        frames: usize,
+    },
+
+    /// Search the detections stored by the database sink, latest first.
+    Query {
+        /// Only detections of this camera.
+        #[arg(long)]
+        camera: Option<String>,
+
+        /// Only detections of this class.
+        #[arg(long)]
+        label: Option<String>,
+
+        /// Only detections at or after this time: an RFC 3339 timestamp or a
+        /// duration ago, such as `2h`.
+        #[arg(long, value_parser = parse_time)]
+        since: Option<SystemTime>,
+
+        /// Only detections at or before this time, in the format of `--since`.
+        #[arg(long, value_parser = parse_time)]
+        until: Option<SystemTime>,
+
+        /// Only detections at least this confident.
+        #[arg(long)]
+        min_confidence: Option<f32>,
+
+        /// Most detections printed.
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
+
+        #[arg(long, value_enum, default_value_t = QueryFormat::Table)]
+        format: QueryFormat,
+
+        /// Database to read instead of the configured one.
+        #[arg(long)]
+        database: Option<PathBuf>,
+    },
+
+    /// Validate the configuration file and report every problem found.
+    CheckConfig,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum QueryFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// An RFC 3339 timestamp, or a duration before now.
+fn parse_time(value: &str) -> Result<SystemTime, String> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(value) {
+        return Ok(time);
+    }
+
+    let ago = humantime::parse_duration(value)
+        .map_err(|_| format!("`{value}` is neither a timestamp nor a duration"))?;
+    SystemTime::now()
+        .checked_sub(ago)
+        .ok_or_else(|| format!("`{value}` is too far in the past"))
+}
@@ -0,0 +1,461 @@
+use crate::config::Config;
+use crate::inference::{Detection, Frame};
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// How long a clip may take to be finalized before it is abandoned.
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A backward jump of the timestamps larger than this means the stream
+/// restarted rather than a reordered frame.
+const DISCONTINUITY: gst::ClockTime = gst::ClockTime::SECOND;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipConfig {
+    /// Record the RTSP cameras streaming H.264, including those added
+    /// through the API.
+    pub enabled: bool,
+    /// Detections with these labels start a clip; all labels when empty.
+    pub labels: Vec<String>,
+    /// How much video before the detected frame a clip starts with, rounded
+    /// up to the previous keyframe.
+    #[serde(with = "humantime_serde")]
+    pub pre_roll: Duration,
+    /// How long a clip goes on after the last detected frame, in stream
+    /// time.
+    #[serde(with = "humantime_serde")]
+    pub post_roll: Duration,
+    pub directory: PathBuf,
+    /// File name of the clips. Supports the `{camera}` and `{time}` (start of
+    /// the clip, UTC) placeholders.
+    pub file_name: String,
+}
+
+impl Default for ClipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            labels: Vec::new(),
+            pre_roll: Duration::from_secs(5),
+            post_roll: Duration::from_secs(10),
+            directory: PathBuf::from("clips"),
+            file_name: "{camera}_{time}.mp4".to_string(),
+        }
+    }
+}
+
+impl ClipConfig {
+    pub const PLACEHOLDERS: &[&str] = &["camera", "time"];
+
+    fn triggers(&self, detection: &Detection) -> bool {
+        self.labels.is_empty() || self.labels.contains(&detection.label)
+    }
+
+    fn path(&self, camera_id: &str) -> PathBuf {
+        // `20241201T101530Z`: sortable and valid on every file system
+        let time = humantime::format_rfc3339_seconds(SystemTime::now())
+            .to_string()
+            .replace(['-', ':'], "");
+
+        self.directory.join(
+            self.file_name
+                .replace("{camera}", camera_id)
+                .replace("{time}", &time),
+        )
+    }
+}
+
+/// The recorder of every camera, created as it starts.
+pub struct Recorders {
+    config: Arc<Config>,
+    cameras: Mutex<HashMap<String, Arc<Recorder>>>,
+}
+
+impl Recorders {
+    pub fn new(config: &Arc<Config>) -> anyhow::Result<Self> {
+        if config.clips.enabled {
+            std::fs::create_dir_all(&config.clips.directory).with_context(|| {
+                format!(
+                    "failed to create clip directory {}",
+                    config.clips.directory.display()
+                )
+            })?;
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            cameras: Mutex::default(),
+        })
+    }
+
+    /// The recorder of a camera, created on first use, unless clips are
+    /// disabled.
+    pub fn get(&self, camera_id: &str) -> Option<Arc<Recorder>> {
+        if !self.config.clips.enabled {
+            return None;
+        }
+
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+        let recorder = cameras
+            .entry(camera_id.to_string())
+            .or_insert_with(|| Arc::new(Recorder::new(self.config.clone(), camera_id)));
+        Some(recorder.clone())
+    }
+
+    /// Start or extend the clip of the frame's camera if one of the
+    /// `detections` calls for it, and return its path.
+    pub fn trigger(
+        &self,
+        config: &Config,
+        frame: &Frame,
+        detections: &[Detection],
+    ) -> Option<PathBuf> {
+        if !detections
+            .iter()
+            .any(|detection| config.clips.triggers(detection))
+        {
+            return None;
+        }
+        let recorder = self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&*frame.camera_id)
+            .cloned()?;
+
+        recorder.trigger(frame.pts)
+    }
+
+    /// Finalize the clip of a camera, e.g. once it was removed, without
+    /// waiting for it to be written out.
+    pub fn stop(&self, camera_id: &str) {
+        let recorder = self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(camera_id)
+            .cloned();
+        if let Some(recorder) = recorder {
+            recorder
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .stop(camera_id);
+        }
+    }
+
+    /// Finalize the clips being recorded, e.g. once the streams stopped,
+    /// and wait for them to be written out.
+    pub fn finish(&self) {
+        let recorders = self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for recorder in recorders {
+            let finishing = {
+                let mut state = recorder
+                    .state
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                state.stop(&recorder.camera_id);
+                std::mem::take(&mut state.finishing)
+            };
+            for thread in finishing {
+                if thread.join().is_err() {
+                    tracing::error!("Finalizing a clip of {} panicked", recorder.camera_id);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the last GOPs of a camera's H.264 stream and writes them, along
+/// with what follows, to a clip when triggered.
+pub struct Recorder {
+    config: Arc<Config>,
+    camera_id: String,
+    /// How far the GOPs go back from the newest access unit: the pre-roll
+    /// of the oldest frame that may be waiting for inference.
+    retention: gst::ClockTime,
+    state: Mutex<RecorderState>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    caps: Option<gst::Caps>,
+    /// The GOPs covering at least the pre-roll, oldest first.
+    gops: VecDeque<Gop>,
+    last_pts: Option<gst::ClockTime>,
+    clip: Option<Clip>,
+    /// The threads writing out the clips that were stopped.
+    finishing: Vec<JoinHandle<()>>,
+}
+
+struct Gop {
+    start: gst::ClockTime,
+    buffers: Vec<gst::Buffer>,
+}
+
+struct Clip {
+    writer: ClipWriter,
+    /// Timestamp of the access unit that ends the clip.
+    until: gst::ClockTime,
+}
+
+impl Recorder {
+    fn new(config: Arc<Config>, camera_id: &str) -> Self {
+        // The queued frames plus the one being inferred
+        let queue_lag =
+            Duration::from_secs_f64((config.queue.depth + 1) as f64 / config.sampling.rate);
+
+        Self {
+            retention: clock_time(config.clips.pre_roll + queue_lag),
+            config,
+            camera_id: camera_id.to_string(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Take an access unit of the encoded stream.
+    pub fn push(&self, sample: &gst::Sample) {
+        let Some(buffer) = sample.buffer_owned() else {
+            return;
+        };
+        let Some(pts) = buffer.pts().or(buffer.dts()) else {
+            return;
+        };
+        let mut guard = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *guard;
+
+        if let Some(caps) = sample.caps()
+            && state.caps.as_deref() != Some(caps)
+        {
+            // A new stream: clips cannot carry on across it
+            state.stop(&self.camera_id);
+            state.gops.clear();
+            state.caps = Some(caps.to_owned());
+        }
+        if state
+            .last_pts
+            .is_some_and(|last| pts + DISCONTINUITY < last)
+        {
+            state.stop(&self.camera_id);
+            state.gops.clear();
+        }
+        state.last_pts = Some(pts);
+
+        if !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+            state.gops.push_back(Gop {
+                start: pts,
+                buffers: Vec::new(),
+            });
+        }
+        // Nothing is decodable before the first keyframe
+        let Some(gop) = state.gops.back_mut() else {
+            return;
+        };
+        gop.buffers.push(buffer.clone());
+
+        // Keep the GOP that starts at or before the retention, and those after
+        while state.gops.len() > 1 && state.gops[1].start + self.retention <= pts {
+            state.gops.pop_front();
+        }
+
+        if let Some(clip) = &mut state.clip {
+            if let Err(err) = clip.writer.push(&buffer) {
+                tracing::warn!("Failed to record {}: {:#}", clip.writer.path.display(), err);
+                state.stop(&self.camera_id);
+            } else if pts >= clip.until {
+                state.stop(&self.camera_id);
+            }
+        }
+    }
+
+    /// Start or extend the clip around the frame at `pts`, which may be well
+    /// behind the newest access unit when inference lags.
+    fn trigger(&self, pts: Option<Duration>) -> Option<PathBuf> {
+        let clips = &self.config.clips;
+        let mut guard = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *guard;
+
+        // Nothing was received yet, e.g. the camera does not stream H.264
+        let last_pts = state.last_pts?;
+        // Frames without a timestamp are taken as the newest
+        let pts = pts.map_or(last_pts, |pts| clock_time(pts).min(last_pts));
+        let until = pts + clock_time(clips.post_roll);
+
+        if let Some(clip) = &mut state.clip {
+            clip.until = clip.until.max(until);
+            return Some(clip.writer.path.clone());
+        }
+
+        let caps = state.caps.clone()?;
+        // Start from the GOP covering the pre-roll of the frame
+        let start = pts.saturating_sub(clock_time(clips.pre_roll));
+        let first = state
+            .gops
+            .iter()
+            .rposition(|gop| gop.start <= start)
+            .unwrap_or(0);
+        state.gops.get(first)?;
+
+        let path = clips.path(&self.camera_id);
+        let started = ClipWriter::start(&caps, path).and_then(|mut writer| {
+            for buffer in state.gops.range(first..).flat_map(|gop| &gop.buffers) {
+                writer.push(buffer)?;
+            }
+            Ok(writer)
+        });
+        match started {
+            Ok(writer) => {
+                tracing::info!("Recording {} to {}", self.camera_id, writer.path.display());
+                let path = writer.path.clone();
+                state.clip = Some(Clip { writer, until });
+                Some(path)
+            }
+            Err(err) => {
+                tracing::warn!("Failed to start a clip of {}: {:#}", self.camera_id, err);
+                None
+            }
+        }
+    }
+}
+
+impl RecorderState {
+    /// Stop the clip being recorded and write it out on its own thread, as
+    /// it may take up to [`FINALIZE_TIMEOUT`] while the state is locked.
+    fn stop(&mut self, camera_id: &str) {
+        self.finishing.retain(|thread| !thread.is_finished());
+        let Some(clip) = self.clip.take() else {
+            return;
+        };
+
+        let camera_id = camera_id.to_string();
+        let writer = clip.writer;
+        let spawned = std::thread::Builder::new()
+            .name(format!("clip-{camera_id}"))
+            .spawn(move || {
+                let path = writer.path.clone();
+                match writer.finish() {
+                    Ok(()) => tracing::info!("Saved clip of {} to {}", camera_id, path.display()),
+                    Err(err) => tracing::warn!("Failed to save {}: {:#}", path.display(), err),
+                }
+            });
+        match spawned {
+            Ok(thread) => self.finishing.push(thread),
+            Err(err) => tracing::warn!("Failed to spawn a thread to save a clip: {}", err),
+        }
+    }
+}
+
+fn clock_time(duration: Duration) -> gst::ClockTime {
+    gst::ClockTime::from_nseconds(duration.as_nanos() as u64)
+}
+
+/// Muxes H.264 access units into an MP4 file without re-encoding them:
+/// `appsrc → h264parse → mp4mux → filesink`.
+struct ClipWriter {
+    path: PathBuf,
+    pipeline: gst::Pipeline,
+    source: gst_app::AppSrc,
+    /// Decoding timestamp of the first access unit, the start of the clip.
+    /// Rebasing on it keeps the decoding timestamps of B-frames, which come
+    /// before their presentation, from going negative.
+    base: Option<gst::ClockTime>,
+}
+
+impl ClipWriter {
+    fn start(caps: &gst::Caps, path: PathBuf) -> anyhow::Result<Self> {
+        let pipeline = gst::Pipeline::new();
+        let source = gst_app::AppSrc::builder()
+            .caps(caps)
+            .format(gst::Format::Time)
+            .build();
+        let h264parse_element = gst::ElementFactory::make("h264parse")
+            .build()
+            .context("failed to create h264parse element")?;
+        let mp4mux_element = gst::ElementFactory::make("mp4mux")
+            .build()
+            .context("failed to create mp4mux element")?;
+        let filesink_element = gst::ElementFactory::make("filesink")
+            .property("location", path.to_string_lossy().as_ref())
+            .build()
+            .context("failed to create filesink element")?;
+
+        let elements = [
+            source.upcast_ref::<gst::Element>(),
+            &h264parse_element,
+            &mp4mux_element,
+            &filesink_element,
+        ];
+        pipeline.add_many(elements)?;
+        gst::Element::link_many(elements)?;
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("failed to start clip pipeline")?;
+
+        Ok(Self {
+            path,
+            pipeline,
+            source,
+            base: None,
+        })
+    }
+
+    fn push(&mut self, buffer: &gst::Buffer) -> anyhow::Result<()> {
+        let base = *self
+            .base
+            .get_or_insert_with(|| buffer.dts().or(buffer.pts()).unwrap_or_default());
+
+        let mut buffer = buffer.copy();
+        {
+            let buffer = buffer.make_mut();
+            let rebase = |time: Option<gst::ClockTime>| {
+                time.map(|time| time.checked_sub(base).unwrap_or_default())
+            };
+            buffer.set_pts(rebase(buffer.pts()));
+            buffer.set_dts(rebase(buffer.dts()));
+        }
+
+        self.source
+            .push_buffer(buffer)
+            .map_err(|err| anyhow::anyhow!("clip pipeline refused a buffer: {err:?}"))?;
+        Ok(())
+    }
+
+    /// End the stream and wait for the muxer to write the file out.
+    fn finish(self) -> anyhow::Result<()> {
+        let _ = self.source.end_of_stream();
+
+        let bus = self.pipeline.bus().context("failed to get bus")?;
+        let result = match bus.timed_pop_filtered(
+            gst::ClockTime::from_nseconds(FINALIZE_TIMEOUT.as_nanos() as u64),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        ) {
+            Some(message) => match message.view() {
+                gst::MessageView::Error(err) => Err(anyhow::anyhow!("{}", err.error())),
+                _ => Ok(()),
+            },
+            None => Err(anyhow::anyhow!(
+                "timed out after {FINALIZE_TIMEOUT:?} waiting for the muxer"
+            )),
+        };
+
+        self.pipeline
+            .set_state(gst::State::Null)
+            .context("failed to stop clip pipeline")?;
+        result
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::Context;
+use gst::prelude::*;
+use gstreamer as gst;
+use std::fmt;
+
+/// A video codec we know how to depayload and decode from RTP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtpVideoCodec {
+    H264,
+    H265,
+    Jpeg,
+}
+
+impl RtpVideoCodec {
+    pub const SUPPORTED: &[RtpVideoCodec] = &[
+        RtpVideoCodec::H264,
+        RtpVideoCodec::H265,
+        RtpVideoCodec::Jpeg,
+    ];
+
+    /// Map the `encoding-name` field of `application/x-rtp` caps to a codec.
+    pub fn from_encoding_name(encoding_name: &str) -> Option<Self> {
+        match encoding_name.to_ascii_uppercase().as_str() {
+            "H264" => Some(RtpVideoCodec::H264),
+            "H265" | "HEVC" => Some(RtpVideoCodec::H265),
+            "JPEG" => Some(RtpVideoCodec::Jpeg),
+            _ => None,
+        }
+    }
+
+    pub fn encoding_name(self) -> &'static str {
+        match self {
+            RtpVideoCodec::H264 => "H264",
+            RtpVideoCodec::H265 => "H265",
+            RtpVideoCodec::Jpeg => "JPEG",
+        }
+    }
+
+    fn depayloader_factory(self) -> &'static str {
+        match self {
+            RtpVideoCodec::H264 => "rtph264depay",
+            RtpVideoCodec::H265 => "rtph265depay",
+            RtpVideoCodec::Jpeg => "rtpjpegdepay",
+        }
+    }
+
+    fn decoder_factory(self) -> &'static str {
+        match self {
+            RtpVideoCodec::H264 => "avdec_h264",
+            RtpVideoCodec::H265 => "avdec_h265",
+            RtpVideoCodec::Jpeg => "jpegdec",
+        }
+    }
+
+    /// Create the depayloader for this codec.
+    pub fn make_depayloader(self) -> anyhow::Result<gst::Element> {
+        let factory = self.depayloader_factory();
+        let depayloader = gst::ElementFactory::make(factory)
+            .build()
+            .with_context(|| format!("failed to create {factory} element"))?;
+
+        // Don't hand half a GOP to the decoder, and ask the camera for a fresh
+        // keyframe when packets are lost.
+        for property in ["wait-for-keyframe", "request-keyframe"] {
+            if depayloader.find_property(property).is_some() {
+                depayloader.set_property(property, true);
+            }
+        }
+
+        Ok(depayloader)
+    }
+
+    /// Create the decoder for this codec.
+    pub fn make_decoder(self) -> anyhow::Result<gst::Element> {
+        let factory = self.decoder_factory();
+        gst::ElementFactory::make(factory)
+            .build()
+            .with_context(|| format!("failed to create {factory} element"))
+    }
+}
+
+impl fmt::Display for RtpVideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.encoding_name())
+    }
+}
+
+/// Describe the supported encodings, e.g. for error messages.
+pub fn supported_encoding_names() -> String {
+    RtpVideoCodec::SUPPORTED
+        .iter()
+        .map(|codec| codec.encoding_name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
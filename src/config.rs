@@ -0,0 +1,811 @@
+use crate::api::ApiConfig;
+use crate::camera;
+use crate::clips::ClipConfig;
+use crate::inference::SupervisionConfig;
+use crate::lines::{CountingConfig, LineConfig};
+use crate::metrics::MetricsConfig;
+use crate::mqtt::MqttConfig;
+use crate::rtsp::RtspConfig;
+use crate::scheduler::BackpressurePolicy;
+use crate::source;
+use crate::store::DatabaseConfig;
+use crate::supervisor::ReconnectPolicy;
+use crate::tracker::TrackingConfig;
+use crate::webhook::WebhookConfig;
+use crate::zones::ZoneConfig;
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The whole configuration file.
+///
+/// Both TOML (`.toml`) and YAML (`.yaml`, `.yml`) are accepted. Every section
+/// is optional and falls back to the defaults below.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub cameras: Vec<CameraConfig>,
+    pub model: ModelConfig,
+    pub thresholds: ThresholdConfig,
+    pub classes: ClassConfig,
+    pub sampling: SamplingConfig,
+    pub video: VideoConfig,
+    pub queue: QueueConfig,
+    pub tracking: TrackingConfig,
+    pub counting: CountingConfig,
+    pub output: OutputConfig,
+    pub mqtt: MqttConfig,
+    pub webhook: WebhookConfig,
+    pub database: DatabaseConfig,
+    pub rtsp: RtspConfig,
+    pub clips: ClipConfig,
+    pub metrics: MetricsConfig,
+    pub api: ApiConfig,
+    pub reconnect: ReconnectPolicy,
+    pub supervision: SupervisionConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    /// Used in logs, file names and topics; `[A-Za-z0-9_-]+`.
+    pub id: String,
+    /// Anything [`source::from_argument`] understands.
+    pub source: String,
+    /// Areas of the picture whose visitors are reported.
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    /// Tripwires counting the objects crossing them in each direction.
+    #[serde(default)]
+    pub lines: Vec<LineConfig>,
+    /// Where the rtsp sink serves this camera, instead of `rtsp.mount_path`.
+    pub rtsp_mount_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    /// A YOLOv8 or YOLO11 detection model exported to ONNX; both share the
+    /// same output layout.
+    pub path: PathBuf,
+    /// The output layout of the model, which decides how it is read.
+    pub version: ModelVersion,
+    /// Tried in order; ONNX Runtime falls back to the CPU.
+    pub execution_providers: Vec<ExecutionProviderKind>,
+    /// Number of inference workers, each with its own model session. The
+    /// frames of a camera are inferred by one worker at a time, in order.
+    pub workers: usize,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("models/yolo11x.onnx"),
+            version: ModelVersion::V8,
+            execution_providers: vec![ExecutionProviderKind::Cuda, ExecutionProviderKind::CoreMl],
+            workers: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ModelVersion {
+    /// YOLOv8, and YOLO11 which kept its output layout.
+    V8,
+}
+
+impl TryFrom<String> for ModelVersion {
+    type Error = String;
+
+    fn try_from(version: String) -> Result<Self, Self::Error> {
+        match version.as_str() {
+            "v8" => Ok(Self::V8),
+            _ => Err(format!(
+                "unsupported model version `{version}`: only v8 models, which YOLO11 \
+                 models also are, can be read"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecutionProviderKind {
+    Cpu,
+    Cuda,
+    TensorRt,
+    CoreMl,
+    DirectMl,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdConfig {
+    /// Detections below this confidence are discarded.
+    pub confidence: f32,
+    /// Overrides of `confidence` by label.
+    pub labels: HashMap<String, f32>,
+    /// Boxes overlapping a more confident one of the same label with an IoU
+    /// above this are discarded; off when unset.
+    pub nms_iou: Option<f32>,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        Self {
+            confidence: 0.0,
+            labels: HashMap::new(),
+            nms_iou: None,
+        }
+    }
+}
+
+/// Which labels of the model are reported at all.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassConfig {
+    /// Only these labels are kept; all when empty.
+    pub allow: Vec<String>,
+    /// These labels are discarded.
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SamplingConfig {
+    /// Frames per second of stream time sent to inference, based on the
+    /// buffer timestamps rather than the nominal frame rate.
+    pub rate: f64,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self { rate: 1.0 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Let the appsink take NV12 and I420 frames as decoded and convert only
+    /// the sampled ones to RGB, instead of converting every frame in
+    /// `videoconvert`.
+    pub accept_yuv: bool,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self { accept_yuv: true }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Number of sampled frames each camera may have waiting for inference.
+    pub depth: usize,
+    /// What to do with new frames when a camera's queue is full. Defaults to
+    /// `drop-oldest` for live sources, so latency stays bounded, and to
+    /// `block` for files and image directories, so no frame is skipped.
+    pub policy: Option<BackpressurePolicy>,
+    /// How long a shutdown on SIGINT or SIGTERM waits for the queued frames
+    /// to be inferred before discarding them.
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Duration,
+}
+
+impl QueueConfig {
+    pub fn policy_for(&self, is_live: bool) -> BackpressurePolicy {
+        self.policy.unwrap_or(if is_live {
+            BackpressurePolicy::DropOldest
+        } else {
+            BackpressurePolicy::Block
+        })
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            depth: 30,
+            policy: None,
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub directory: PathBuf,
+    /// File name of each cropped detection. Supports the `{camera}`,
+    /// `{frame}`, `{pts}` (milliseconds), `{track}`, `{label}` and
+    /// `{confidence}` placeholders.
+    pub crop_file_name: String,
+    pub crop_mode: CropMode,
+    /// With `best-per-track`, also save the first crop of every track as
+    /// soon as the track is confirmed.
+    pub save_first_crop: bool,
+    /// File name of the first crops, with the placeholders of
+    /// `crop_file_name`.
+    pub first_crop_file_name: String,
+    /// JSON Lines file of the `json-lines` sink, or `-` for stdout.
+    pub events: PathBuf,
+    /// Where detections are delivered; every sink runs concurrently.
+    pub sinks: Vec<SinkKind>,
+    /// Number of inferences each sink may have waiting before new ones of
+    /// live sources are dropped for that sink; the others and events wait.
+    pub sink_queue_depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CropMode {
+    /// A crop of every detection on every inferred frame, saved by the
+    /// inference workers before the frame is handed to the sinks, so that
+    /// events only refer to crops that were written.
+    EveryFrame,
+    /// One crop per track, the one with the best confidence × size ×
+    /// sharpness, saved when the track ends.
+    BestPerTrack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// PNG crops of the detections, named after `crop_file_name`.
+    Crops,
+    /// One detection event per inferred frame, written to `events`.
+    JsonLines,
+    /// Detection events published to the broker of the `mqtt` section.
+    Mqtt,
+    /// Batches of detection events POSTed to the `webhook` URLs.
+    Webhook,
+    /// Every detection stored in the SQLite database of the `database`
+    /// section, searchable with the `query` subcommand.
+    Database,
+    /// The inferred frames with their detections drawn onto them, served
+    /// over RTSP as configured in the `rtsp` section.
+    Rtsp,
+}
+
+/// What the name of a crop file is made of.
+pub struct CropName<'a> {
+    pub camera_id: &'a str,
+    pub frame_id: usize,
+    pub pts: Option<Duration>,
+    pub track_id: Option<u64>,
+    pub label: &'a str,
+    pub confidence: f32,
+}
+
+impl OutputConfig {
+    const PLACEHOLDERS: &[&str] = &["camera", "frame", "pts", "track", "label", "confidence"];
+
+    pub fn crop_path(&self, name: &CropName) -> PathBuf {
+        self.directory
+            .join(fill_crop_name(&self.crop_file_name, name))
+    }
+
+    pub fn first_crop_path(&self, name: &CropName) -> PathBuf {
+        self.directory
+            .join(fill_crop_name(&self.first_crop_file_name, name))
+    }
+}
+
+fn fill_crop_name(template: &str, name: &CropName) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+
+    template
+        .replace("{camera}", name.camera_id)
+        .replace("{frame}", &name.frame_id.to_string())
+        .replace(
+            "{pts}",
+            &optional(name.pts.map(|pts| pts.as_millis().to_string())),
+        )
+        .replace("{track}", &optional(name.track_id.map(|id| id.to_string())))
+        .replace("{label}", name.label)
+        .replace("{confidence}", &format!("{:.2}", name.confidence))
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("."),
+            crop_file_name: "{camera}_frame_{frame}_{label}_{confidence}.png".to_string(),
+            crop_mode: CropMode::EveryFrame,
+            save_first_crop: false,
+            first_crop_file_name: "{camera}_track_{track}_first_{label}_{confidence}.png"
+                .to_string(),
+            events: PathBuf::from("-"),
+            sinks: vec![SinkKind::Crops],
+            sink_queue_depth: 256,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of `trace`, `debug`, `info`, `warn` and `error`.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Full,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+}
+
+impl Config {
+    /// Where the rtsp sink serves the annotated stream of `camera_id`.
+    pub fn rtsp_mount_path(&self, camera_id: &str) -> String {
+        self.cameras
+            .iter()
+            .find(|camera| camera.id == camera_id)
+            .and_then(|camera| camera.rtsp_mount_path.clone())
+            .unwrap_or_else(|| self.rtsp.mount_path.replace("{camera}", camera_id))
+    }
+
+    /// Read a configuration file, picking the format from its extension.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        match extension {
+            "toml" => toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            "yaml" | "yml" => serde_yaml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            _ => bail!(
+                "unknown configuration format for {} (expected .toml, .yaml or .yml)",
+                path.display()
+            ),
+        }
+    }
+
+    /// Check everything that deserialization alone cannot, returning every
+    /// problem found instead of stopping at the first one.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.cameras.is_empty() {
+            problems.push("no cameras configured".to_string());
+        }
+        let mut seen_ids = HashSet::new();
+        for (index, camera) in self.cameras.iter().enumerate() {
+            if !camera::is_valid_id(&camera.id) {
+                problems.push(format!(
+                    "cameras[{index}]: id `{}` must only contain letters, digits, `-` and `_`",
+                    camera.id
+                ));
+            }
+            if !seen_ids.insert(camera.id.as_str()) {
+                problems.push(format!("cameras[{index}]: duplicate id `{}`", camera.id));
+            }
+            if let Err(err) = source::from_argument(&camera.source) {
+                problems.push(format!("cameras[{index}] ({}): {err:#}", camera.id));
+            }
+
+            let mut zone_names = HashSet::new();
+            for zone in &camera.zones {
+                let prefix = format!("cameras[{index}] ({}): zone `{}`", camera.id, zone.name);
+                if !zone_names.insert(zone.name.as_str()) {
+                    problems.push(format!("{prefix} is defined twice"));
+                }
+                if zone.polygon.len() < 3 {
+                    problems.push(format!("{prefix} needs at least 3 corners"));
+                }
+                if zone
+                    .polygon
+                    .iter()
+                    .flatten()
+                    .any(|value| !(0.0..=1.0).contains(value))
+                {
+                    problems.push(format!(
+                        "{prefix} has corners outside of the frame: coordinates are between 0 and 1"
+                    ));
+                }
+                if zone
+                    .dwell_interval
+                    .is_some_and(|interval| interval.is_zero())
+                {
+                    problems.push(format!("{prefix}: dwell_interval must be positive"));
+                }
+                if !self.tracking.enabled {
+                    problems.push(format!("{prefix} needs tracking.enabled"));
+                }
+            }
+
+            let mut line_names = HashSet::new();
+            for line in &camera.lines {
+                let prefix = format!("cameras[{index}] ({}): line `{}`", camera.id, line.name);
+                if !line_names.insert(line.name.as_str()) {
+                    problems.push(format!("{prefix} is defined twice"));
+                }
+                if line.start == line.end {
+                    problems.push(format!("{prefix} starts where it ends"));
+                }
+                if [line.start, line.end]
+                    .iter()
+                    .flatten()
+                    .any(|value| !(0.0..=1.0).contains(value))
+                {
+                    problems.push(format!(
+                        "{prefix} goes outside of the frame: coordinates are between 0 and 1"
+                    ));
+                }
+                if !self.tracking.enabled {
+                    problems.push(format!("{prefix} needs tracking.enabled"));
+                }
+            }
+        }
+
+        if !self.model.path.is_file() {
+            problems.push(format!(
+                "model.path: {} does not exist",
+                self.model.path.display()
+            ));
+        }
+        if self.model.workers == 0 {
+            problems.push("model.workers must be at least 1".to_string());
+        }
+
+        if !(0.0..=1.0).contains(&self.thresholds.confidence) {
+            problems.push(format!(
+                "thresholds.confidence must be between 0 and 1, got {}",
+                self.thresholds.confidence
+            ));
+        }
+        for (label, confidence) in &self.thresholds.labels {
+            if !(0.0..=1.0).contains(confidence) {
+                problems.push(format!(
+                    "thresholds.labels.{label} must be between 0 and 1, got {confidence}"
+                ));
+            }
+        }
+        if let Some(nms_iou) = self.thresholds.nms_iou
+            && !(0.0..=1.0).contains(&nms_iou)
+        {
+            problems.push(format!(
+                "thresholds.nms_iou must be between 0 and 1, got {nms_iou}"
+            ));
+        }
+        for label in &self.classes.allow {
+            if self.classes.deny.contains(label) {
+                problems.push(format!("classes: `{label}` is both allowed and denied"));
+            }
+        }
+        if !(self.sampling.rate.is_finite() && self.sampling.rate > 0.0) {
+            problems.push(format!(
+                "sampling.rate must be a positive number, got {}",
+                self.sampling.rate
+            ));
+        }
+
+        if self.queue.depth == 0 {
+            problems.push("queue.depth must be at least 1".to_string());
+        }
+
+        let tracking = &self.tracking;
+        for (name, value) in [
+            ("iou_threshold", tracking.iou_threshold),
+            ("high_confidence", tracking.high_confidence),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                problems.push(format!(
+                    "tracking.{name} must be between 0 and 1, got {value}"
+                ));
+            }
+        }
+        if tracking.min_hits == 0 {
+            problems.push("tracking.min_hits must be at least 1".to_string());
+        }
+        if self.counting.interval < Duration::from_secs(1) {
+            problems.push("counting.interval must be at least 1s".to_string());
+        }
+        if self.counting.rolling_window.is_zero() {
+            problems.push("counting.rolling_window must be positive".to_string());
+        }
+
+        if self.output.directory.exists() && !self.output.directory.is_dir() {
+            problems.push(format!(
+                "output.directory: {} is not a directory",
+                self.output.directory.display()
+            ));
+        }
+        for (name, template) in [
+            ("crop_file_name", &self.output.crop_file_name),
+            ("first_crop_file_name", &self.output.first_crop_file_name),
+        ] {
+            for placeholder in placeholders(template) {
+                if !OutputConfig::PLACEHOLDERS.contains(&placeholder) {
+                    problems.push(format!(
+                        "output.{name}: unknown placeholder `{{{placeholder}}}`"
+                    ));
+                }
+            }
+        }
+        if self.output.crop_mode == CropMode::BestPerTrack && !self.tracking.enabled {
+            problems.push("output.crop_mode = best-per-track needs tracking.enabled".to_string());
+        }
+        if self.output.events.is_dir() {
+            problems.push(format!(
+                "output.events: {} is a directory",
+                self.output.events.display()
+            ));
+        }
+        let mut seen_sinks = HashSet::new();
+        for sink in &self.output.sinks {
+            if !seen_sinks.insert(sink) {
+                problems.push(format!("output.sinks: {sink:?} is listed twice"));
+            }
+        }
+        if self.output.sink_queue_depth == 0 {
+            problems.push("output.sink_queue_depth must be at least 1".to_string());
+        }
+
+        if self.output.sinks.contains(&SinkKind::Mqtt) {
+            let mqtt = &self.mqtt;
+            if mqtt.qos().is_none() {
+                problems.push(format!("mqtt.qos must be 0, 1 or 2, got {}", mqtt.qos));
+            }
+            for (name, template) in [
+                ("topic", Some(&mqtt.topic)),
+                ("thumbnail_topic", mqtt.thumbnail_topic.as_ref()),
+            ] {
+                for placeholder in template
+                    .into_iter()
+                    .flat_map(|template| placeholders(template))
+                {
+                    if !MqttConfig::PLACEHOLDERS.contains(&placeholder) {
+                        problems.push(format!(
+                            "mqtt.{name}: unknown placeholder `{{{placeholder}}}`"
+                        ));
+                    }
+                }
+            }
+            for placeholder in placeholders(&mqtt.event_topic) {
+                if !MqttConfig::EVENT_PLACEHOLDERS.contains(&placeholder) {
+                    problems.push(format!(
+                        "mqtt.event_topic: unknown placeholder `{{{placeholder}}}`"
+                    ));
+                }
+            }
+            if mqtt.thumbnail_size == 0 {
+                problems.push("mqtt.thumbnail_size must be at least 1".to_string());
+            }
+            if mqtt.keep_alive < Duration::from_secs(1) {
+                problems.push("mqtt.keep_alive must be at least 1s".to_string());
+            }
+            for (name, path) in [
+                ("ca_file", &mqtt.ca_file),
+                ("client_cert_file", &mqtt.client_cert_file),
+                ("client_key_file", &mqtt.client_key_file),
+            ] {
+                if let Some(path) = path
+                    && !path.is_file()
+                {
+                    problems.push(format!("mqtt.{name}: {} does not exist", path.display()));
+                }
+            }
+            if mqtt.client_cert_file.is_some() != mqtt.client_key_file.is_some() {
+                problems.push(
+                    "mqtt.client_cert_file and mqtt.client_key_file must be set together"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.output.sinks.contains(&SinkKind::Webhook) {
+            let webhook = &self.webhook;
+            if webhook.urls.is_empty() {
+                problems.push("webhook.urls must not be empty".to_string());
+            }
+            for url in &webhook.urls {
+                if !(url.starts_with("http://") || url.starts_with("https://")) {
+                    problems.push(format!("webhook.urls: `{url}` is not an HTTP(S) URL"));
+                }
+            }
+            if webhook.batch_size == 0 {
+                problems.push("webhook.batch_size must be at least 1".to_string());
+            }
+            if webhook.initial_backoff > webhook.max_backoff {
+                problems.push(
+                    "webhook.initial_backoff must not be greater than webhook.max_backoff"
+                        .to_string(),
+                );
+            }
+            if let Some(spool_directory) = &webhook.spool_directory
+                && spool_directory.exists()
+                && !spool_directory.is_dir()
+            {
+                problems.push(format!(
+                    "webhook.spool_directory: {} is not a directory",
+                    spool_directory.display()
+                ));
+            }
+        }
+
+        if self.output.sinks.contains(&SinkKind::Database) && self.database.path.is_dir() {
+            problems.push(format!(
+                "database.path: {} is a directory",
+                self.database.path.display()
+            ));
+        }
+
+        if self.output.sinks.contains(&SinkKind::Rtsp) {
+            for placeholder in placeholders(&self.rtsp.mount_path) {
+                if !RtspConfig::PLACEHOLDERS.contains(&placeholder) {
+                    problems.push(format!(
+                        "rtsp.mount_path: unknown placeholder `{{{placeholder}}}`"
+                    ));
+                }
+            }
+            if self.rtsp.bitrate == 0 {
+                problems.push("rtsp.bitrate must be at least 1".to_string());
+            }
+
+            let mut mount_paths = HashSet::new();
+            for camera in &self.cameras {
+                let path = self.rtsp_mount_path(&camera.id);
+                if !path.starts_with('/') {
+                    problems.push(format!(
+                        "camera {}: RTSP mount path `{path}` must start with `/`",
+                        camera.id
+                    ));
+                }
+                if !mount_paths.insert(path.clone()) {
+                    problems.push(format!(
+                        "camera {}: RTSP mount path `{path}` is already used",
+                        camera.id
+                    ));
+                }
+            }
+        }
+
+        if self.clips.enabled {
+            for placeholder in placeholders(&self.clips.file_name) {
+                if !ClipConfig::PLACEHOLDERS.contains(&placeholder) {
+                    problems.push(format!(
+                        "clips.file_name: unknown placeholder `{{{placeholder}}}`"
+                    ));
+                }
+            }
+            if !self.clips.file_name.contains("{time}") {
+                problems.push(
+                    "clips.file_name must contain `{time}`, or every clip overwrites the last"
+                        .to_string(),
+                );
+            }
+            if self.clips.directory.exists() && !self.clips.directory.is_dir() {
+                problems.push(format!(
+                    "clips.directory: {} is not a directory",
+                    self.clips.directory.display()
+                ));
+            }
+            if self.clips.post_roll.is_zero() {
+                problems.push("clips.post_roll must be positive".to_string());
+            }
+        }
+
+        if self.metrics.enabled
+            && self
+                .metrics
+                .address
+                .parse::<std::net::SocketAddr>()
+                .is_err()
+        {
+            problems.push(format!(
+                "metrics.address: `{}` is not an address and port",
+                self.metrics.address
+            ));
+        }
+
+        if self.api.enabled {
+            if self.api.address.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!(
+                    "api.address: `{}` is not an address and port",
+                    self.api.address
+                ));
+            }
+            if self.api.recent_detections == 0 {
+                problems.push("api.recent_detections must be at least 1".to_string());
+            }
+            if self
+                .api
+                .token
+                .as_ref()
+                .is_some_and(|token| token.trim().is_empty())
+            {
+                problems.push("api.token must not be empty".to_string());
+            }
+        }
+
+        let supervision = &self.supervision;
+        if supervision.initial_backoff > supervision.max_backoff {
+            problems.push(
+                "supervision.initial_backoff must not be greater than supervision.max_backoff"
+                    .to_string(),
+            );
+        }
+        if supervision.failure_threshold == 0 {
+            problems.push("supervision.failure_threshold must be at least 1".to_string());
+        }
+
+        let reconnect = &self.reconnect;
+        if reconnect.initial_backoff > reconnect.max_backoff {
+            problems.push(
+                "reconnect.initial_backoff must not be greater than reconnect.max_backoff"
+                    .to_string(),
+            );
+        }
+        if reconnect.multiplier < 1.0 {
+            problems.push(format!(
+                "reconnect.multiplier must be at least 1, got {}",
+                reconnect.multiplier
+            ));
+        }
+        if !(0.0..=1.0).contains(&reconnect.jitter) {
+            problems.push(format!(
+                "reconnect.jitter must be between 0 and 1, got {}",
+                reconnect.jitter
+            ));
+        }
+        if reconnect.no_data_timeout.is_zero() {
+            problems.push("reconnect.no_data_timeout must not be zero".to_string());
+        }
+
+        if tracing::Level::from_str(&self.logging.level).is_err() {
+            problems.push(format!(
+                "logging.level: unknown level `{}`",
+                self.logging.level
+            ));
+        }
+
+        problems
+    }
+
+    /// Fail with every problem found by [`Config::problems`].
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            bail!("invalid configuration:\n  - {}", problems.join("\n  - "));
+        }
+
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> tracing::Level {
+        tracing::Level::from_str(&self.logging.level).unwrap_or(tracing::Level::INFO)
+    }
+}
+
+/// The `{name}` placeholders used in a template.
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+}
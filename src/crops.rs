@@ -0,0 +1,252 @@
+use crate::config::{Config, CropMode, CropName};
+use crate::event::{Event, TrackEventKind};
+use crate::inference::{self, Detection, Frame};
+use crate::metrics::metrics;
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How much longer than `tracking.max_age` the best crop of a track is kept
+/// without an update before it is saved anyway, in case its end never comes,
+/// e.g. because its camera was removed.
+const STALE_CROP_SLACK: Duration = Duration::from_secs(10);
+
+/// Save the crop of every detection with the configured file name, and
+/// return the path of each crop that could be written.
+pub fn save_crops(
+    config: &Config,
+    frame: &Frame,
+    detections: &[Detection],
+) -> Vec<Option<PathBuf>> {
+    detections
+        .iter()
+        .map(|detection| {
+            let path = config.output.crop_path(&crop_name(frame, detection));
+            let image = inference::crop(&frame.image, &detection.bounding_box);
+            match save_png(&image, &path) {
+                Ok(()) => Some(path),
+                Err(err) => {
+                    metrics().sink_failed("crops");
+                    tracing::warn!(
+                        "Failed to save a crop of frame {} of {}: {:#}",
+                        frame.id,
+                        frame.camera_id,
+                        err
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn crop_name<'a>(frame: &'a Frame, detection: &'a Detection) -> CropName<'a> {
+    CropName {
+        camera_id: &frame.camera_id,
+        frame_id: frame.id,
+        pts: frame.pts,
+        track_id: detection.track_id,
+        label: &detection.label,
+        confidence: detection.confidence,
+    }
+}
+
+/// Write the PNG next to `path` first, so that an interrupted write never
+/// leaves a truncated file behind.
+fn save_png(image: &DynamicImage, path: &Path) -> anyhow::Result<()> {
+    let partial = path.with_extension("png.partial");
+    let mut file = File::create(&partial)
+        .with_context(|| format!("failed to create {}", partial.display()))?;
+    image
+        .write_to(&mut file, ImageFormat::Png)
+        .with_context(|| format!("failed to write {}", partial.display()))?;
+    std::fs::rename(&partial, path)
+        .with_context(|| format!("failed to rename {}", partial.display()))
+}
+
+/// How good a crop is as the picture of its track: confidence × share of
+/// the frame covered × sharpness.
+fn crop_score(crop: &DynamicImage, confidence: f32, frame: &DynamicImage) -> f32 {
+    let frame_area = (frame.width() as f32 * frame.height() as f32).max(1.0);
+    let size = crop.width() as f32 * crop.height() as f32 / frame_area;
+
+    confidence * size * sharpness(crop)
+}
+
+/// Variance of the Laplacian of the luma: blurry crops have few edges.
+fn sharpness(crop: &DynamicImage) -> f32 {
+    let luma = crop.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let pixel = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f32;
+    let mut sum = 0.0;
+    let mut sum_of_squares = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian = 4.0 * pixel(x, y)
+                - pixel(x - 1, y)
+                - pixel(x + 1, y)
+                - pixel(x, y - 1)
+                - pixel(x, y + 1);
+            sum += laplacian;
+            sum_of_squares += laplacian * laplacian;
+        }
+    }
+
+    let count = ((width - 2) * (height - 2)) as f32;
+    let mean = sum / count;
+    sum_of_squares / count - mean * mean
+}
+
+/// The best crop of a live track so far.
+struct BestCrop {
+    score: f32,
+    image: DynamicImage,
+    frame_id: usize,
+    pts: Option<Duration>,
+    label: String,
+    confidence: f32,
+    updated_at: Instant,
+}
+
+/// Saves the best crop of every track as a PNG file. Crops of every frame are
+/// saved before their inference is dispatched instead; see [`Inference::new`].
+pub struct CropSink {
+    config: Arc<Config>,
+    best_crops: HashMap<(String, u64), BestCrop>,
+}
+
+impl CropSink {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            best_crops: HashMap::new(),
+        }
+    }
+
+    fn keep_best(&mut self, frame: &Frame, detection: &Detection) -> anyhow::Result<()> {
+        // Only confirmed tracks have an ID; tentative ones may be noise
+        let Some(track_id) = detection.track_id else {
+            return Ok(());
+        };
+
+        let image = inference::crop(&frame.image, &detection.bounding_box);
+        let score = crop_score(&image, detection.confidence, &frame.image);
+        let key = (frame.camera_id.to_string(), track_id);
+
+        let is_first = !self.best_crops.contains_key(&key);
+        if is_first && self.config.output.save_first_crop {
+            let path = self
+                .config
+                .output
+                .first_crop_path(&crop_name(frame, detection));
+            save_png(&image, &path)?;
+        }
+
+        if let Some(best) = self.best_crops.get_mut(&key) {
+            best.updated_at = Instant::now();
+        }
+        if is_first || self.best_crops[&key].score < score {
+            self.best_crops.insert(
+                key,
+                BestCrop {
+                    score,
+                    image,
+                    frame_id: frame.id,
+                    pts: frame.pts,
+                    label: detection.label.clone(),
+                    confidence: detection.confidence,
+                    updated_at: Instant::now(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Save and forget the best crops of the tracks not seen for a while.
+    fn save_stale(&mut self) -> anyhow::Result<()> {
+        let max_age = self.config.tracking.max_age + STALE_CROP_SLACK;
+        let stale = self
+            .best_crops
+            .iter()
+            .filter(|(_, best)| best.updated_at.elapsed() > max_age)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in stale {
+            if let Some(best) = self.best_crops.remove(&key) {
+                self.save_best(&key.0, key.1, &best)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save_best(&self, camera_id: &str, track_id: u64, best: &BestCrop) -> anyhow::Result<()> {
+        let path = self.config.output.crop_path(&CropName {
+            camera_id,
+            frame_id: best.frame_id,
+            pts: best.pts,
+            track_id: Some(track_id),
+            label: &best.label,
+            confidence: best.confidence,
+        });
+
+        save_png(&best.image, &path)
+    }
+}
+
+impl DetectionSink for CropSink {
+    fn name(&self) -> &'static str {
+        "crops"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        if self.config.output.crop_mode == CropMode::BestPerTrack {
+            for detection in &inference.detections {
+                self.keep_best(&inference.frame, detection)?;
+            }
+            self.save_stale()?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        let Event::Track(event) = event else {
+            return Ok(());
+        };
+        if event.kind != TrackEventKind::Ended {
+            return Ok(());
+        }
+
+        match self
+            .best_crops
+            .remove(&(event.camera_id.clone(), event.track_id))
+        {
+            Some(best) => self.save_best(&event.camera_id, event.track_id, &best),
+            None => Ok(()),
+        }
+    }
+
+    fn idle(&mut self) -> anyhow::Result<()> {
+        self.save_stale()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        for ((camera_id, track_id), best) in std::mem::take(&mut self.best_crops) {
+            self.save_best(&camera_id, track_id, &best)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use crate::inference::Detection;
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use yolo_rs::BoundingBox;
+
+/// Everything found on one inferred frame.
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionEvent {
+    pub camera_id: String,
+    pub frame_id: usize,
+    /// Presentation timestamp of the frame in milliseconds, if it had one.
+    pub pts_ms: Option<u64>,
+    /// Wall-clock time of the inference, RFC 3339 in UTC.
+    pub timestamp: String,
+    /// File stem of the model that produced the detections.
+    pub model: String,
+    pub width: u32,
+    pub height: u32,
+    pub detections: Vec<DetectedObject>,
+    /// The clip recording this frame, if one of its detections started or
+    /// extended one.
+    pub clip_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectedObject {
+    pub label: String,
+    pub confidence: f32,
+    pub bounding_box: NormalizedBox,
+    /// The track this object belongs to, once the tracker confirmed it.
+    pub track_id: Option<u64>,
+    /// Where the crop of this object was saved, if it was.
+    pub crop_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackEventKind {
+    Started,
+    Ended,
+}
+
+/// A track of the same object across frames started or ended.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackEvent {
+    pub kind: TrackEventKind,
+    pub camera_id: String,
+    pub track_id: u64,
+    pub label: String,
+    /// Wall-clock time of the event, RFC 3339 in UTC.
+    pub timestamp: String,
+    /// Presentation timestamps of the first and last frames of the track,
+    /// in milliseconds.
+    pub first_seen_ms: u64,
+    pub last_seen_ms: u64,
+    /// Number of inferred frames the object was found on.
+    pub frames: u32,
+    pub best_confidence: f32,
+}
+
+impl TrackEvent {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        kind: TrackEventKind,
+        camera_id: &str,
+        track_id: u64,
+        label: &str,
+        first_seen: Duration,
+        last_seen: Duration,
+        frames: u32,
+        best_confidence: f32,
+    ) -> Self {
+        Self {
+            kind,
+            camera_id: camera_id.to_string(),
+            track_id,
+            label: label.to_string(),
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            first_seen_ms: first_seen.as_millis() as u64,
+            last_seen_ms: last_seen.as_millis() as u64,
+            frames,
+            best_confidence,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneEventKind {
+    ZoneEnter,
+    /// The object is still in the zone, reported every `dwell_interval`.
+    ZoneDwell,
+    ZoneExit,
+    /// The object stayed in the zone longer than its loitering threshold.
+    Loitering,
+}
+
+/// A tracked object entered, left or lingered in a zone.
+#[derive(Debug, Clone, Serialize)]
+pub struct ZoneEvent {
+    pub kind: ZoneEventKind,
+    pub camera_id: String,
+    pub zone: String,
+    pub track_id: u64,
+    pub label: String,
+    /// Wall-clock time of the event, RFC 3339 in UTC.
+    pub timestamp: String,
+    /// Presentation timestamp of the frame the event was found on, in
+    /// milliseconds.
+    pub pts_ms: u64,
+    /// Time spent in the zone so far, or in total on exit.
+    pub dwell_ms: u64,
+}
+
+impl ZoneEvent {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        kind: ZoneEventKind,
+        camera_id: &str,
+        zone: &str,
+        track_id: u64,
+        label: &str,
+        time: Duration,
+        dwell: Duration,
+    ) -> Self {
+        Self {
+            kind,
+            camera_id: camera_id.to_string(),
+            zone: zone.to_string(),
+            track_id,
+            label: label.to_string(),
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            pts_ms: time.as_millis() as u64,
+            dwell_ms: dwell.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossingDirection {
+    /// From the left of the line to its right, looking from its start
+    /// towards its end.
+    In,
+    Out,
+}
+
+/// A tracked object crossed a line.
+#[derive(Debug, Clone, Serialize)]
+pub struct LineCrossingEvent {
+    pub camera_id: String,
+    pub line: String,
+    pub track_id: u64,
+    pub label: String,
+    pub direction: CrossingDirection,
+    /// Wall-clock time of the event, RFC 3339 in UTC.
+    pub timestamp: String,
+    /// Presentation timestamp of the frame the crossing was found on, in
+    /// milliseconds.
+    pub pts_ms: u64,
+    /// Objects with this label that crossed the line in each direction
+    /// during the last `counting.rolling_window`, this one included.
+    pub count_in: u64,
+    pub count_out: u64,
+}
+
+/// How many objects with a label crossed a line during one counting
+/// interval.
+#[derive(Debug, Clone, Serialize)]
+pub struct LineCountEvent {
+    pub camera_id: String,
+    pub line: String,
+    pub label: String,
+    /// Bounds of the interval, RFC 3339 in UTC.
+    pub interval_start: String,
+    pub interval_end: String,
+    pub count_in: u64,
+    pub count_out: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// An inference worker could not load the model.
+    ModelLoad,
+    /// The model failed on a frame.
+    Inference,
+    /// An inference worker panicked.
+    WorkerPanic,
+}
+
+/// An inference worker or a frame failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorEvent {
+    pub kind: ErrorKind,
+    /// The camera whose frame failed; `None` when the worker itself did.
+    pub camera_id: Option<String>,
+    pub worker: usize,
+    pub message: String,
+    /// Consecutive failures so far: frames of the camera, or attempts of
+    /// the worker.
+    pub failures: u32,
+    /// Wall-clock time of the event, RFC 3339 in UTC.
+    pub timestamp: String,
+}
+
+impl ErrorEvent {
+    pub fn new(
+        kind: ErrorKind,
+        camera_id: Option<&str>,
+        worker: usize,
+        message: String,
+        failures: u32,
+    ) -> Self {
+        Self {
+            kind,
+            camera_id: camera_id.map(str::to_string),
+            worker,
+            message,
+            failures,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        }
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            ErrorKind::ModelLoad => "model_load",
+            ErrorKind::Inference => "inference",
+            ErrorKind::WorkerPanic => "worker_panic",
+        }
+    }
+}
+
+/// Something that happened to a tracked object or to inference, delivered
+/// to the sinks besides the detection events.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Track(TrackEvent),
+    Zone(ZoneEvent),
+    LineCrossing(LineCrossingEvent),
+    LineCount(LineCountEvent),
+    Error(ErrorEvent),
+}
+
+impl Event {
+    pub fn camera_id(&self) -> &str {
+        match self {
+            Event::Track(event) => &event.camera_id,
+            Event::Zone(event) => &event.camera_id,
+            Event::LineCrossing(event) => &event.camera_id,
+            Event::LineCount(event) => &event.camera_id,
+            Event::Error(event) => event.camera_id.as_deref().unwrap_or_default(),
+        }
+    }
+
+    /// The label of the object, or the kind of an error.
+    pub fn label(&self) -> &str {
+        match self {
+            Event::Track(event) => &event.label,
+            Event::Zone(event) => &event.label,
+            Event::LineCrossing(event) => &event.label,
+            Event::LineCount(event) => &event.label,
+            Event::Error(event) => event.kind_name(),
+        }
+    }
+
+    /// The `type` tag of the event in [`Record`].
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Event::Track(_) => "track",
+            Event::Zone(_) => "zone",
+            Event::LineCrossing(_) => "line_crossing",
+            Event::LineCount(_) => "line_count",
+            Event::Error(_) => "error",
+        }
+    }
+
+    pub fn record(&self) -> Record<'_> {
+        match self {
+            Event::Track(event) => Record::Track(event),
+            Event::Zone(event) => Record::Zone(event),
+            Event::LineCrossing(event) => Record::LineCrossing(event),
+            Event::LineCount(event) => Record::LineCount(event),
+            Event::Error(event) => Record::Error(event),
+        }
+    }
+}
+
+/// Any event, tagged with its `type` for outputs that mix them.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record<'a> {
+    Detection(&'a DetectionEvent),
+    Track(&'a TrackEvent),
+    Zone(&'a ZoneEvent),
+    LineCrossing(&'a LineCrossingEvent),
+    LineCount(&'a LineCountEvent),
+    Error(&'a ErrorEvent),
+}
+
+/// A bounding box with coordinates relative to the frame size, in `0..=1`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NormalizedBox {
+    pub x1: f32,
+    pub y1: f32,
+    pub x2: f32,
+    pub y2: f32,
+}
+
+impl NormalizedBox {
+    pub fn new(bounding_box: &BoundingBox, width: u32, height: u32) -> Self {
+        let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+        let normalize = |value: f32, size: f32| (value / size).clamp(0.0, 1.0);
+
+        Self {
+            x1: normalize(bounding_box.x1, width),
+            y1: normalize(bounding_box.y1, height),
+            x2: normalize(bounding_box.x2, width),
+            y2: normalize(bounding_box.y2, height),
+        }
+    }
+}
+
+impl DetectionEvent {
+    /// Describe `detections` found on a `width`×`height` frame.
+    ///
+    /// `crop_paths` is either empty or holds, for every detection, the path
+    /// of its crop if it was saved.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        camera_id: &str,
+        frame_id: usize,
+        pts: Option<Duration>,
+        model: &Path,
+        width: u32,
+        height: u32,
+        detections: &[Detection],
+        crop_paths: &[Option<PathBuf>],
+    ) -> Self {
+        Self {
+            camera_id: camera_id.to_string(),
+            frame_id,
+            pts_ms: pts.map(|pts| pts.as_millis() as u64),
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            model: model
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            width,
+            height,
+            detections: detections
+                .iter()
+                .enumerate()
+                .map(|(index, detection)| DetectedObject {
+                    label: detection.label.clone(),
+                    confidence: detection.confidence,
+                    bounding_box: NormalizedBox::new(&detection.bounding_box, width, height),
+                    track_id: detection.track_id,
+                    crop_path: crop_paths.get(index).cloned().flatten(),
+                })
+                .collect(),
+            clip_path: None,
+        }
+    }
+}
+
+/// Appends events to a JSON Lines file or stdout, one line per event tagged
+/// with its `type`.
+pub struct JsonLinesSink {
+    output: Box<dyn Write + Send>,
+}
+
+impl JsonLinesSink {
+    /// Open `path` for appending, or stdout if `path` is `-`.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let output: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            Box::new(BufWriter::new(file))
+        };
+
+        Ok(Self { output })
+    }
+
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record).context("failed to serialize event")?;
+        line.push(b'\n');
+
+        self.output.write_all(&line)?;
+        self.output.flush()?;
+
+        Ok(())
+    }
+}
+
+impl DetectionSink for JsonLinesSink {
+    fn name(&self) -> &'static str {
+        "json-lines"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        self.write(&Record::Detection(&inference.event))
+    }
+
+    fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        self.write(&event.record())
+    }
+}
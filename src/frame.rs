@@ -0,0 +1,279 @@
+use anyhow::{Context, bail};
+use gstreamer as gst;
+use gstreamer_video as gst_video;
+use image::RgbImage;
+
+/// Raw formats the appsink accepts when YUV input is enabled, in order of
+/// preference. YUV frames are converted to RGB only when they are sampled,
+/// which lets `videoconvert` pass the decoder output through untouched.
+pub const YUV_AND_RGB_FORMATS: &[gst_video::VideoFormat] = &[
+    gst_video::VideoFormat::Nv12,
+    gst_video::VideoFormat::I420,
+    gst_video::VideoFormat::Rgb,
+];
+
+/// Copy a decoded sample into a tightly packed RGB image, honouring the row
+/// stride of every plane.
+pub fn sample_to_rgb_image(sample: &gst::Sample) -> anyhow::Result<RgbImage> {
+    let buffer = sample.buffer().context("sample has no buffer")?;
+    let caps = sample.caps().context("sample has no caps")?;
+    let video_info =
+        gst_video::VideoInfo::from_caps(caps).context("sample caps are not raw video")?;
+    let frame = gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video_info)
+        .context("failed to map video frame")?;
+
+    let width = frame.width() as usize;
+    let height = frame.height() as usize;
+    let strides = frame.plane_stride();
+    let matrix = YuvMatrix::from_colorimetry(&video_info.colorimetry());
+
+    let pixels = match frame.format() {
+        gst_video::VideoFormat::Rgb => {
+            packed_rgb_to_rgb(frame.plane_data(0)?, strides[0] as usize, width, height)
+        }
+        gst_video::VideoFormat::Nv12 => yuv_to_rgb(
+            width,
+            height,
+            matrix,
+            Plane::new(frame.plane_data(0)?, strides[0]),
+            Chroma::Interleaved(Plane::new(frame.plane_data(1)?, strides[1])),
+        ),
+        gst_video::VideoFormat::I420 => yuv_to_rgb(
+            width,
+            height,
+            matrix,
+            Plane::new(frame.plane_data(0)?, strides[0]),
+            Chroma::Planar(
+                Plane::new(frame.plane_data(1)?, strides[1]),
+                Plane::new(frame.plane_data(2)?, strides[2]),
+            ),
+        ),
+        format => bail!("unsupported raw video format {:?}", format),
+    };
+
+    RgbImage::from_raw(width as u32, height as u32, pixels).context("invalid frame size")
+}
+
+/// Drop the per-row padding of a packed RGB plane.
+fn packed_rgb_to_rgb(data: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+    let row_size = width * 3;
+    let mut pixels = Vec::with_capacity(row_size * height);
+
+    for row in 0..height {
+        pixels.extend_from_slice(&data[row * stride..row * stride + row_size]);
+    }
+
+    pixels
+}
+
+/// One plane of a frame and its row stride in bytes.
+struct Plane<'a> {
+    data: &'a [u8],
+    stride: usize,
+}
+
+impl<'a> Plane<'a> {
+    fn new(data: &'a [u8], stride: i32) -> Self {
+        Self {
+            data,
+            stride: stride as usize,
+        }
+    }
+}
+
+/// The 4:2:0 subsampled chroma planes.
+enum Chroma<'a> {
+    /// NV12: one plane of interleaved U and V samples.
+    Interleaved(Plane<'a>),
+    /// I420: separate U and V planes.
+    Planar(Plane<'a>, Plane<'a>),
+}
+
+impl Chroma<'_> {
+    fn uv(&self, x: usize, y: usize) -> (u8, u8) {
+        let (x, y) = (x / 2, y / 2);
+        match self {
+            Chroma::Interleaved(uv) => {
+                let offset = y * uv.stride + x * 2;
+                (uv.data[offset], uv.data[offset + 1])
+            }
+            Chroma::Planar(u, v) => (u.data[y * u.stride + x], v.data[y * v.stride + x]),
+        }
+    }
+}
+
+/// The YCbCr → RGB matrix and range of a stream.
+#[derive(Debug, Clone, Copy)]
+struct YuvMatrix {
+    kr: f32,
+    kb: f32,
+    full_range: bool,
+}
+
+impl YuvMatrix {
+    fn from_colorimetry(colorimetry: &gst_video::VideoColorimetry) -> Self {
+        let (kr, kb) = match colorimetry.matrix() {
+            gst_video::VideoColorMatrix::Bt709 => (0.2126, 0.0722),
+            gst_video::VideoColorMatrix::Bt2020 => (0.2627, 0.0593),
+            _ => (0.299, 0.114),
+        };
+
+        Self {
+            kr,
+            kb,
+            full_range: colorimetry.range() == gst_video::VideoColorRange::Range0_255,
+        }
+    }
+}
+
+fn yuv_to_rgb(
+    width: usize,
+    height: usize,
+    matrix: YuvMatrix,
+    luma: Plane<'_>,
+    chroma: Chroma<'_>,
+) -> Vec<u8> {
+    let YuvMatrix { kr, kb, full_range } = matrix;
+    let kg = 1.0 - kr - kb;
+    let (y_offset, y_scale, c_scale) = if full_range {
+        (0.0, 1.0, 1.0)
+    } else {
+        (16.0, 255.0 / 219.0, 255.0 / 224.0)
+    };
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+
+    for y in 0..height {
+        let luma_row = &luma.data[y * luma.stride..];
+        for x in 0..width {
+            let (u, v) = chroma.uv(x, y);
+
+            let luma = (luma_row[x] as f32 - y_offset) * y_scale;
+            let cb = (u as f32 - 128.0) * c_scale;
+            let cr = (v as f32 - 128.0) * c_scale;
+
+            let r = luma + 2.0 * (1.0 - kr) * cr;
+            let b = luma + 2.0 * (1.0 - kb) * cb;
+            let g = (luma - kr * r - kb * b) / kg;
+
+            pixels.extend_from_slice(&[
+                r.round().clamp(0.0, 255.0) as u8,
+                g.round().clamp(0.0, 255.0) as u8,
+                b.round().clamp(0.0, 255.0) as u8,
+            ]);
+        }
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BT601_LIMITED: YuvMatrix = YuvMatrix {
+        kr: 0.299,
+        kb: 0.114,
+        full_range: false,
+    };
+    const BT601_FULL: YuvMatrix = YuvMatrix {
+        full_range: true,
+        ..BT601_LIMITED
+    };
+
+    /// Padding bytes, which must never show up in the output.
+    const PAD: u8 = 0xee;
+
+    /// Compare pixels allowing for the rounding of the YUV coefficients.
+    fn assert_pixels_near(actual: &[u8], expected: &[[u8; 3]]) {
+        assert_eq!(actual.len(), expected.len() * 3);
+        for (index, (actual, expected)) in actual.chunks(3).zip(expected).enumerate() {
+            let near = actual
+                .iter()
+                .zip(expected)
+                .all(|(&a, &e)| a.abs_diff(e) <= 2);
+            assert!(near, "pixel {index}: {actual:?} is not {expected:?}");
+        }
+    }
+
+    #[test]
+    fn drops_the_padding_of_rgb_rows() {
+        // 3 pixels of 3 bytes, padded to a stride of 12
+        #[rustfmt::skip]
+        let data = [
+            1, 2, 3, 4, 5, 6, 7, 8, 9, PAD, PAD, PAD,
+            10, 11, 12, 13, 14, 15, 16, 17, 18, PAD, PAD, PAD,
+        ];
+
+        assert_eq!(
+            packed_rgb_to_rgb(&data, 12, 3, 2),
+            (1..=18).collect::<Vec<u8>>()
+        );
+    }
+
+    #[test]
+    fn converts_limited_range_nv12() {
+        // 3x2 pixels: red on the first two columns, blue on the last one. Rows
+        // are padded to a stride of 4.
+        #[rustfmt::skip]
+        let luma = [
+            81, 81, 41, PAD,
+            81, 81, 41, PAD,
+        ];
+        let uv = [90, 240, 240, 110];
+
+        let pixels = yuv_to_rgb(
+            3,
+            2,
+            BT601_LIMITED,
+            Plane::new(&luma, 4),
+            Chroma::Interleaved(Plane::new(&uv, 4)),
+        );
+
+        let (red, blue) = ([255, 0, 0], [0, 0, 255]);
+        assert_pixels_near(&pixels, &[red, red, blue, red, red, blue]);
+    }
+
+    #[test]
+    fn maps_the_limited_range_to_full_black_and_white() {
+        let luma = [16, 235, PAD, PAD];
+        let (u, v) = ([128, PAD], [128, PAD]);
+
+        let pixels = yuv_to_rgb(
+            2,
+            1,
+            BT601_LIMITED,
+            Plane::new(&luma, 4),
+            Chroma::Planar(Plane::new(&u, 2), Plane::new(&v, 2)),
+        );
+
+        assert_eq!(pixels, [0, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn converts_full_range_i420() {
+        // 5x2 pixels: red, green and gray blocks, the last one cut in half.
+        // Rows are padded to a stride of 8 and 4.
+        #[rustfmt::skip]
+        let luma = [
+            76, 76, 150, 150, 128, PAD, PAD, PAD,
+            76, 76, 150, 150, 128, PAD, PAD, PAD,
+        ];
+        let u = [85, 44, 128, PAD];
+        let v = [255, 21, 128, PAD];
+
+        let pixels = yuv_to_rgb(
+            5,
+            2,
+            BT601_FULL,
+            Plane::new(&luma, 8),
+            Chroma::Planar(Plane::new(&u, 4), Plane::new(&v, 4)),
+        );
+
+        let (red, green, gray) = ([255, 0, 0], [0, 255, 0], [128, 128, 128]);
+        assert_pixels_near(
+            &pixels,
+            &[red, red, green, green, gray, red, red, green, green, gray],
+        );
+    }
+}
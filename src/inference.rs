@@ -0,0 +1,517 @@
+use crate::camera::Cameras;
+use crate::clips::Recorders;
+use crate::config::{Config, ExecutionProviderKind, ModelVersion};
+use crate::event::{ErrorEvent, ErrorKind, Event};
+use crate::lines::Counters;
+use crate::metrics::metrics;
+use crate::scheduler::FairQueue;
+use crate::sink::{Dispatcher, Inference};
+use crate::tracker::{self, Trackers};
+use crate::zones::Zones;
+use anyhow::{Context, bail};
+use image::DynamicImage;
+use ort::execution_providers::{
+    CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider,
+    DirectMLExecutionProvider, ExecutionProviderDispatch, TensorRTExecutionProvider,
+};
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use yolo_rs::model::YoloModelSession;
+use yolo_rs::{BoundingBox, image_to_yolo_input_tensor, inference};
+
+/// How often a worker waiting to restart checks whether it is still needed.
+const RESTART_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A sampled frame waiting for inference.
+pub struct Frame {
+    pub camera_id: Arc<str>,
+    pub id: usize,
+    /// Presentation timestamp of the buffer the frame was decoded from.
+    pub pts: Option<Duration>,
+    pub image: DynamicImage,
+    /// Whether it comes from a live source, whose sinks may skip it rather
+    /// than hold up inference.
+    pub live: bool,
+}
+
+/// Initialize ONNX runtime with the configured execution providers.
+pub fn init_runtime(config: &Config) -> anyhow::Result<()> {
+    let execution_providers = config
+        .model
+        .execution_providers
+        .iter()
+        .map(|kind| -> ExecutionProviderDispatch {
+            match kind {
+                ExecutionProviderKind::Cpu => CPUExecutionProvider::default().build(),
+                ExecutionProviderKind::Cuda => CUDAExecutionProvider::default().build(),
+                ExecutionProviderKind::TensorRt => TensorRTExecutionProvider::default().build(),
+                ExecutionProviderKind::CoreMl => CoreMLExecutionProvider::default().build(),
+                ExecutionProviderKind::DirectMl => DirectMLExecutionProvider::default().build(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    ort::init()
+        .with_execution_providers(execution_providers)
+        .with_telemetry(true)
+        .commit()
+        .context("failed to initialize ONNX runtime")?;
+
+    Ok(())
+}
+
+/// What the inference workers follow across frames, per camera.
+///
+/// Their locks ignore poisoning: a worker that panics while holding one
+/// leaves at most its frame half applied, and is restarted.
+pub struct Analysis {
+    pub trackers: Trackers,
+    pub zones: Zones,
+    pub counters: Counters,
+    pub recorders: Recorders,
+}
+
+impl Analysis {
+    /// Track the `detections` of `frame`, filling in their `track_id`, and
+    /// follow them across the zones and lines of its camera. Returns the
+    /// track, zone and line events, in that order.
+    ///
+    /// Callers hold the claim of the camera's lane, so that every step sees
+    /// the frames of a camera in order and none interleaves with another.
+    pub fn update(
+        &self,
+        config: &Config,
+        frame: &Frame,
+        detections: &mut [Detection],
+    ) -> Vec<Event> {
+        let track_events = self.trackers.update(&config.tracking, frame, detections);
+        let zone_events = self.zones.update(config, frame, detections, &track_events);
+        let line_events = self
+            .counters
+            .update(config, frame, detections, &track_events);
+
+        track_events
+            .into_iter()
+            .map(Event::Track)
+            .chain(zone_events.into_iter().map(Event::Zone))
+            .chain(line_events)
+            .collect()
+    }
+
+    /// End what is followed of a camera that stopped and forget it, so that
+    /// it starts afresh if it comes back. Returns the events of the tracks
+    /// that ended, like [`Analysis::update`].
+    ///
+    /// Callers make sure that no frame of the camera is left to infer.
+    pub fn end_camera(&self, config: &Config, camera_id: &str) -> Vec<Event> {
+        self.recorders.stop(camera_id);
+        let track_events = self.trackers.remove(camera_id);
+        let zone_events = self.zones.remove(config, camera_id, &track_events);
+        let line_events = self.counters.remove(config, camera_id);
+
+        track_events
+            .into_iter()
+            .map(Event::Track)
+            .chain(zone_events.into_iter().map(Event::Zone))
+            .chain(line_events)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisionConfig {
+    /// Delay before restarting an inference worker that failed, doubled
+    /// after every failure in a row.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Frames in a row that must fail inference before their camera is in
+    /// the error state, and model loads in a row that must fail before the
+    /// run does, unless another worker has the model loaded.
+    pub failure_threshold: u32,
+}
+
+impl Default for SupervisionConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            failure_threshold: 3,
+        }
+    }
+}
+
+/// Spawn the configured number of inference workers, each with its own model
+/// session, that share `queue` until it is closed and drained, run the
+/// detections through `analysis` and hand every result to `sinks`.
+///
+/// Workers that fail are restarted; their errors, and those of the frames
+/// they could not infer, are dispatched as [`Event::Error`]. Once none of them
+/// could load the model `supervision.failure_threshold` times in a row, the
+/// cameras are aborted, since their frames would wait forever.
+pub fn spawn_workers(
+    config: Arc<Config>,
+    queue: Arc<FairQueue<Frame>>,
+    analysis: Arc<Analysis>,
+    sinks: Arc<Dispatcher>,
+    cameras: Arc<Cameras>,
+) -> Vec<JoinHandle<()>> {
+    let loaded = Arc::new(AtomicUsize::new(0));
+
+    (0..config.model.workers)
+        .map(|worker_id| {
+            let worker = Worker {
+                id: worker_id,
+                config: config.clone(),
+                queue: queue.clone(),
+                analysis: analysis.clone(),
+                sinks: sinks.clone(),
+                cameras: cameras.clone(),
+                loaded: loaded.clone(),
+            };
+
+            std::thread::Builder::new()
+                .name(format!("inference-{worker_id}"))
+                .spawn(move || worker.supervise())
+                .expect("failed to spawn inference worker")
+        })
+        .collect()
+}
+
+/// An object found by the model, already through the [`DetectionFilter`].
+#[derive(Clone)]
+pub struct Detection {
+    pub label: String,
+    pub confidence: f32,
+    pub bounding_box: BoundingBox,
+    /// Filled in by the tracker.
+    pub track_id: Option<u64>,
+}
+
+/// Which of the model's detections are kept; everything by default.
+#[derive(Debug, Default)]
+pub struct DetectionFilter {
+    pub min_confidence: f32,
+    /// Overrides of `min_confidence` by label.
+    pub label_confidence: HashMap<String, f32>,
+    /// Labels kept; all when empty.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    /// Drop the boxes overlapping a more confident one of the same label
+    /// with an IoU above this.
+    pub nms_iou: Option<f32>,
+}
+
+impl DetectionFilter {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            min_confidence: config.thresholds.confidence,
+            label_confidence: config.thresholds.labels.clone(),
+            allow: config.classes.allow.clone(),
+            deny: config.classes.deny.clone(),
+            nms_iou: config.thresholds.nms_iou,
+        }
+    }
+
+    fn keeps(&self, label: &str, confidence: f32) -> bool {
+        let min_confidence = self
+            .label_confidence
+            .get(label)
+            .copied()
+            .unwrap_or(self.min_confidence);
+
+        confidence >= min_confidence
+            && (self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == label))
+            && !self.deny.iter().any(|denied| denied == label)
+    }
+
+    fn suppress(&self, mut detections: Vec<Detection>) -> Vec<Detection> {
+        let Some(nms_iou) = self.nms_iou else {
+            return detections;
+        };
+
+        detections.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        let mut kept: Vec<Detection> = Vec::with_capacity(detections.len());
+        for detection in detections {
+            if !kept.iter().any(|other| {
+                other.label == detection.label
+                    && tracker::iou(&other.bounding_box, &detection.bounding_box) > nms_iou
+            }) {
+                kept.push(detection);
+            }
+        }
+
+        kept
+    }
+}
+
+/// A loaded model session plus the filter applied to its output.
+pub struct Detector {
+    model: YoloModelSession,
+    filter: DetectionFilter,
+}
+
+impl Detector {
+    /// Load the model, labelling its classes with the names an Ultralytics
+    /// export stores in its metadata, or the COCO ones if it has none.
+    pub fn load(
+        model_path: &Path,
+        version: ModelVersion,
+        filter: DetectionFilter,
+    ) -> anyhow::Result<Self> {
+        let model_path = model_path.to_string_lossy().into_owned();
+        let mut model = match version {
+            ModelVersion::V8 => YoloModelSession::from_filename_v8(&model_path),
+        }
+        .with_context(|| format!("failed to load YOLO model {model_path}"))?;
+
+        let names = model
+            .session
+            .metadata()
+            .and_then(|metadata| metadata.custom("names"))
+            .with_context(|| format!("failed to read the metadata of {model_path}"))?;
+        let names = names.map(|names| parse_class_names(&names));
+        if let Some(names) = names.filter(|names| !names.is_empty()) {
+            model.labels = names.into_iter().map(Into::into).collect();
+        }
+
+        Ok(Self { model, filter })
+    }
+
+    /// Load the configured model, making sure that it knows the labels of
+    /// the class filters and per-label thresholds.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let detector = Self::load(
+            &config.model.path,
+            config.model.version,
+            DetectionFilter::from_config(config),
+        )?;
+
+        let labels = detector.model.get_labels();
+        let unknown = config
+            .classes
+            .allow
+            .iter()
+            .map(|label| ("classes.allow", label))
+            .chain(
+                config
+                    .classes
+                    .deny
+                    .iter()
+                    .map(|label| ("classes.deny", label)),
+            )
+            .chain(
+                config
+                    .thresholds
+                    .labels
+                    .keys()
+                    .map(|label| ("thresholds.labels", label)),
+            )
+            .filter(|(_, label)| !labels.iter().any(|known| known.as_str() == label.as_str()))
+            .map(|(field, label)| format!("{field}: the model has no class `{label}`"))
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            bail!("{}", unknown.join("; "));
+        }
+
+        Ok(detector)
+    }
+
+    pub fn model(&self) -> &YoloModelSession {
+        &self.model
+    }
+
+    pub fn detect(&self, image: &DynamicImage) -> anyhow::Result<Vec<Detection>> {
+        let yolo_input = image_to_yolo_input_tensor(image);
+        let yolo_output =
+            inference(&self.model, yolo_input.view()).context("failed to run inference")?;
+
+        let detections = yolo_output
+            .into_iter()
+            .filter(|entity| self.filter.keeps(&entity.label, entity.confidence))
+            .map(|entity| Detection {
+                label: entity.label.to_string(),
+                confidence: entity.confidence,
+                bounding_box: entity.bounding_box,
+                track_id: None,
+            })
+            .collect();
+
+        Ok(self.filter.suppress(detections))
+    }
+}
+
+/// Parse the Python dict of class names by index: `{0: 'person', …}`.
+fn parse_class_names(names: &str) -> Vec<String> {
+    let mut class_names = Vec::new();
+    let mut rest = names;
+    while let Some(colon) = rest.find(':') {
+        rest = rest[colon + 1..].trim_start();
+        let Some(quote) = rest.chars().next().filter(|c| matches!(c, '\'' | '"')) else {
+            break;
+        };
+        rest = &rest[1..];
+        let Some(end) = rest.find(quote) else {
+            break;
+        };
+        class_names.push(rest[..end].to_string());
+        rest = &rest[end + 1..];
+    }
+
+    class_names
+}
+
+/// Cut the area of `bounding_box` out of `image`.
+pub fn crop(image: &DynamicImage, bounding_box: &BoundingBox) -> DynamicImage {
+    let BoundingBox { x1, x2, y1, y2 } = *bounding_box;
+
+    image.crop_imm(x1 as _, y1 as _, (x2 - x1) as u32, (y2 - y1) as u32)
+}
+
+struct Worker {
+    id: usize,
+    config: Arc<Config>,
+    queue: Arc<FairQueue<Frame>>,
+    analysis: Arc<Analysis>,
+    sinks: Arc<Dispatcher>,
+    cameras: Arc<Cameras>,
+    /// Number of workers with a loaded model.
+    loaded: Arc<AtomicUsize>,
+}
+
+impl Worker {
+    /// Run until the queue is drained, restarting with backoff whenever the
+    /// model cannot be loaded or the worker panics.
+    fn supervise(&self) {
+        let supervision = &self.config.supervision;
+        let mut failures = 0;
+
+        loop {
+            let mut inferred = 0;
+            let (kind, message) = match Detector::from_config(&self.config) {
+                Ok(detector) => {
+                    self.loaded.fetch_add(1, Ordering::SeqCst);
+                    let run = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.run(&detector, &mut inferred)
+                    }));
+                    self.loaded.fetch_sub(1, Ordering::SeqCst);
+                    match run {
+                        Ok(()) => return,
+                        Err(panic) => (ErrorKind::WorkerPanic, panic_message(&*panic)),
+                    }
+                }
+                Err(err) => (ErrorKind::ModelLoad, format!("{err:#}")),
+            };
+
+            // A worker that got frames through counts as a successful start
+            if inferred > 0 {
+                failures = 0;
+            }
+            failures += 1;
+            tracing::error!("Inference worker {} failed: {}", self.id, message);
+            let gave_up = kind == ErrorKind::ModelLoad
+                && failures >= supervision.failure_threshold
+                && self.loaded.load(Ordering::SeqCst) == 0;
+            if gave_up {
+                self.cameras.abort(format!(
+                    "no inference worker could load the model: {message}"
+                ));
+            }
+            self.sinks.dispatch_event(Event::Error(ErrorEvent::new(
+                kind, None, self.id, message, failures,
+            )));
+
+            let delay = supervision
+                .initial_backoff
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(supervision.max_backoff);
+            tracing::info!("Restarting inference worker {} in {:?}", self.id, delay);
+            let until = Instant::now() + delay;
+            while Instant::now() < until {
+                // Nothing left to restart for
+                if self.queue.is_drained() {
+                    return;
+                }
+                std::thread::sleep(RESTART_POLL_INTERVAL.min(until - Instant::now()));
+            }
+        }
+    }
+
+    fn run(&self, detector: &Detector, inferred: &mut u64) {
+        let (config, analysis, sinks) = (&*self.config, &self.analysis, &self.sinks);
+
+        // Holding the claim of the frame's camera until its results are
+        // dispatched keeps the other workers off that camera, so that its
+        // frames go through the trackers, zones and counters in order
+        while let Some((frame, _claim)) = self.queue.pop() {
+            tracing::info!(
+                "Inferring frame {} (pts {:?}) of {}",
+                frame.id,
+                frame.pts,
+                frame.camera_id
+            );
+            let now = Instant::now();
+
+            let mut detections = match detector.detect(&frame.image) {
+                Ok(detections) => detections,
+                Err(err) => {
+                    let message = format!("{err:#}");
+                    tracing::warn!(
+                        "Failed to infer frame {} of {}: {}",
+                        frame.id,
+                        frame.camera_id,
+                        message
+                    );
+                    let failures = self.cameras.inference_failed(&frame.camera_id, &message);
+                    sinks.dispatch_event(Event::Error(ErrorEvent::new(
+                        ErrorKind::Inference,
+                        Some(&*frame.camera_id),
+                        self.id,
+                        message,
+                        failures,
+                    )));
+                    continue;
+                }
+            };
+            let elapsed = now.elapsed();
+            *inferred += 1;
+            self.cameras.inference_succeeded(&frame.camera_id);
+
+            tracing::info!(
+                "Found {} entities on {}, elapsed: {:?}",
+                detections.len(),
+                frame.camera_id,
+                elapsed
+            );
+            let labels = detections
+                .iter()
+                .map(|detection| detection.label.as_str())
+                .collect::<Vec<_>>();
+            metrics().inferred(&frame.camera_id, elapsed, &labels);
+
+            let events = analysis.update(config, &frame, &mut detections);
+            let clip_path = analysis.recorders.trigger(config, &frame, &detections);
+            for event in events {
+                sinks.dispatch_event(event);
+            }
+            sinks.dispatch(Inference::new(config, frame, detections, clip_path));
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string())
+}
@@ -0,0 +1,559 @@
+use crate::config::Config;
+use crate::event::{
+    CrossingDirection, Event, LineCountEvent, LineCrossingEvent, TrackEvent, TrackEventKind,
+};
+use crate::inference::{Detection, Frame};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a live camera goes without frames before its intervals are
+/// reported from the wall clock instead.
+const QUIET_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineConfig {
+    /// Used in events and topics; unique per camera.
+    pub name: String,
+    /// Ends of the line as `[x, y]`, normalized to the frame size. Objects
+    /// crossing from the left of `start` → `end` to its right go `in`.
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+    /// Only objects with these labels are counted; all labels when empty.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl LineConfig {
+    fn counts(&self, label: &str) -> bool {
+        self.labels.is_empty() || self.labels.iter().any(|l| l == label)
+    }
+
+    /// The direction in which the move from `from` to `to` crosses the line,
+    /// if it does.
+    fn crossing(&self, from: [f32; 2], to: [f32; 2]) -> Option<CrossingDirection> {
+        let (side_from, side_to) = (
+            side(self.start, self.end, from),
+            side(self.start, self.end, to),
+        );
+        let (side_start, side_end) = (side(from, to, self.start), side(from, to, self.end));
+        if side_start * side_end >= 0.0 {
+            return None;
+        }
+
+        // The image y axis points down, so a positive side is on the right
+        if side_from < 0.0 && side_to > 0.0 {
+            Some(CrossingDirection::In)
+        } else if side_from > 0.0 && side_to < 0.0 {
+            Some(CrossingDirection::Out)
+        } else {
+            None
+        }
+    }
+}
+
+/// Which side of `a` → `b` the point is on: the sign of their cross product.
+fn side(a: [f32; 2], b: [f32; 2], point: [f32; 2]) -> f32 {
+    (b[0] - a[0]) * (point[1] - a[1]) - (b[1] - a[1]) * (point[0] - a[0])
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CountingConfig {
+    /// Length of the intervals whose totals are reported in `line_count`
+    /// events, aligned on the wall clock. Frames are placed on it by their
+    /// timestamps, from the time the first frame of their camera was counted.
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Span of the counts reported with every `line_crossing` event.
+    #[serde(with = "humantime_serde")]
+    pub rolling_window: Duration,
+}
+
+impl Default for CountingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15 * 60),
+            rolling_window: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The line crossing counters of every camera, shared by the inference
+/// workers through [`Analysis::update`](crate::inference::Analysis::update).
+#[derive(Default)]
+pub struct Counters {
+    cameras: Mutex<HashMap<String, CameraCounters>>,
+}
+
+impl Counters {
+    /// Follow the tracked `detections` of `frame` across the lines of its
+    /// camera and return the crossings, along with the totals of the
+    /// intervals that elapsed since the last frame.
+    pub fn update(
+        &self,
+        config: &Config,
+        frame: &Frame,
+        detections: &[Detection],
+        track_events: &[TrackEvent],
+    ) -> Vec<Event> {
+        let Some(camera) = config
+            .cameras
+            .iter()
+            .find(|camera| *camera.id == *frame.camera_id)
+        else {
+            return Vec::new();
+        };
+        if camera.lines.is_empty() {
+            return Vec::new();
+        }
+
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+        let counters = cameras
+            .entry(camera.id.clone())
+            .or_insert_with(|| CameraCounters::new(&config.counting, frame));
+
+        let time = counters.clock.advance(frame);
+        let mut events = counters.flush_elapsed(&config.counting, &camera.id, &camera.lines, time);
+        events.extend(counters.update(&config.counting, &camera.lines, frame, detections, time));
+        counters.end_tracks(track_events);
+        events
+    }
+
+    /// The totals of the intervals that elapsed since the last frame of the
+    /// live cameras that went quiet, e.g. because their stream stalled.
+    pub fn flush_quiet(&self, config: &Config) -> Vec<Event> {
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+
+        config
+            .cameras
+            .iter()
+            .filter_map(|camera| Some((camera, cameras.get_mut(&camera.id)?)))
+            .flat_map(|(camera, counters)| match counters.clock.quiet_time() {
+                Some(time) => {
+                    counters.flush_elapsed(&config.counting, &camera.id, &camera.lines, time)
+                }
+                None => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// The totals of the current intervals of a camera, forgetting it, e.g.
+    /// once it was removed.
+    pub fn remove(&self, config: &Config, camera_id: &str) -> Vec<Event> {
+        let Some(mut counters) = self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(camera_id)
+        else {
+            return Vec::new();
+        };
+
+        config
+            .cameras
+            .iter()
+            .find(|camera| camera.id == camera_id)
+            .map(|camera| counters.flush(&config.counting, &camera.id, &camera.lines))
+            .unwrap_or_default()
+    }
+
+    /// The totals of the current intervals, e.g. once the streams stopped.
+    pub fn finish(&self, config: &Config) -> Vec<Event> {
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+
+        config
+            .cameras
+            .iter()
+            .filter_map(|camera| Some((camera, cameras.get_mut(&camera.id)?)))
+            .flat_map(|(camera, counters)| {
+                counters.flush(&config.counting, &camera.id, &camera.lines)
+            })
+            .collect()
+    }
+}
+
+struct CameraCounters {
+    /// Last bottom-center point of every track, normalized.
+    last_points: HashMap<u64, [f32; 2]>,
+    /// Tracks are counted once per line and direction, so that an object
+    /// standing on a line does not count again at every jitter.
+    counted: HashSet<(usize, u64, CrossingDirection)>,
+    /// Crossings within the rolling window, keyed by line index and label.
+    recent: HashMap<(usize, String), VecDeque<(SystemTime, CrossingDirection)>>,
+    /// Totals of the current interval, keyed by line index and label.
+    totals: HashMap<(usize, String), (u64, u64)>,
+    interval_start: SystemTime,
+    clock: StreamClock,
+}
+
+impl CameraCounters {
+    /// Counters starting at `frame`, the first of their camera.
+    fn new(config: &CountingConfig, frame: &Frame) -> Self {
+        let clock = StreamClock::new(frame);
+
+        Self {
+            last_points: HashMap::new(),
+            counted: HashSet::new(),
+            recent: HashMap::new(),
+            totals: HashMap::new(),
+            interval_start: interval_start(clock.origin.1, config.interval),
+            clock,
+        }
+    }
+
+    /// Count the crossings of `detections`, found on `frame` at `time`.
+    fn update(
+        &mut self,
+        config: &CountingConfig,
+        lines: &[LineConfig],
+        frame: &Frame,
+        detections: &[Detection],
+        time: SystemTime,
+    ) -> Vec<Event> {
+        let (width, height) = (
+            frame.image.width().max(1) as f32,
+            frame.image.height().max(1) as f32,
+        );
+        let mut events = Vec::new();
+
+        for detection in detections {
+            // Only confirmed tracks have an ID; tentative ones may be noise
+            let Some(track_id) = detection.track_id else {
+                continue;
+            };
+            let bounding_box = &detection.bounding_box;
+            let point = [
+                (bounding_box.x1 + bounding_box.x2) / 2.0 / width,
+                bounding_box.y2 / height,
+            ];
+            let Some(last_point) = self.last_points.insert(track_id, point) else {
+                continue;
+            };
+
+            for (line_index, line) in lines.iter().enumerate() {
+                if !line.counts(&detection.label) {
+                    continue;
+                }
+                let Some(direction) = line.crossing(last_point, point) else {
+                    continue;
+                };
+                if !self.counted.insert((line_index, track_id, direction)) {
+                    continue;
+                }
+
+                let key = (line_index, detection.label.clone());
+                let total = self.totals.entry(key.clone()).or_default();
+                match direction {
+                    CrossingDirection::In => total.0 += 1,
+                    CrossingDirection::Out => total.1 += 1,
+                }
+
+                let recent = self.recent.entry(key).or_default();
+                recent.push_back((time, direction));
+                while recent.front().is_some_and(|&(at, _)| {
+                    time.duration_since(at).unwrap_or_default() > config.rolling_window
+                }) {
+                    recent.pop_front();
+                }
+                let count_in = recent
+                    .iter()
+                    .filter(|(_, direction)| *direction == CrossingDirection::In)
+                    .count() as u64;
+
+                events.push(Event::LineCrossing(LineCrossingEvent {
+                    camera_id: frame.camera_id.to_string(),
+                    line: line.name.clone(),
+                    track_id,
+                    label: detection.label.clone(),
+                    direction,
+                    timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                    pts_ms: self.clock.last.as_millis() as u64,
+                    count_in,
+                    count_out: recent.len() as u64 - count_in,
+                }));
+            }
+        }
+
+        events
+    }
+
+    fn end_tracks(&mut self, track_events: &[TrackEvent]) {
+        for ended in track_events
+            .iter()
+            .filter(|event| event.kind == TrackEventKind::Ended)
+        {
+            self.last_points.remove(&ended.track_id);
+            self.counted
+                .retain(|&(_, track_id, _)| track_id != ended.track_id);
+        }
+    }
+
+    /// Report and reset the totals if their interval is over at `time`.
+    fn flush_elapsed(
+        &mut self,
+        config: &CountingConfig,
+        camera_id: &str,
+        lines: &[LineConfig],
+        time: SystemTime,
+    ) -> Vec<Event> {
+        if time < self.interval_start + config.interval {
+            return Vec::new();
+        }
+
+        let events = self.flush(config, camera_id, lines);
+        self.interval_start = interval_start(time, config.interval);
+        events
+    }
+
+    /// Report and reset the totals of the current interval.
+    fn flush(
+        &mut self,
+        config: &CountingConfig,
+        camera_id: &str,
+        lines: &[LineConfig],
+    ) -> Vec<Event> {
+        let interval_start = humantime::format_rfc3339(self.interval_start).to_string();
+        let interval_end =
+            humantime::format_rfc3339(self.interval_start + config.interval).to_string();
+
+        self.totals
+            .drain()
+            .map(|((line_index, label), (count_in, count_out))| {
+                Event::LineCount(LineCountEvent {
+                    camera_id: camera_id.to_string(),
+                    line: lines[line_index].name.clone(),
+                    label,
+                    interval_start: interval_start.clone(),
+                    interval_end: interval_end.clone(),
+                    count_in,
+                    count_out,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Places the frames of a camera on the wall clock by their timestamps,
+/// from the time its first frame was counted, so that files are counted in
+/// stream time however fast they are read.
+struct StreamClock {
+    /// Stream time of the first frame and its time on the wall clock.
+    origin: (Duration, SystemTime),
+    /// Stream time of the last frame.
+    last: Duration,
+    last_seen: Instant,
+    live: bool,
+    started_at: Instant,
+}
+
+impl StreamClock {
+    fn new(frame: &Frame) -> Self {
+        let now = frame.pts.unwrap_or_default();
+
+        Self {
+            origin: (now, SystemTime::now()),
+            last: now,
+            last_seen: Instant::now(),
+            live: frame.live,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Move on to `frame` and return its time on the wall clock.
+    fn advance(&mut self, frame: &Frame) -> SystemTime {
+        let now = frame.pts.unwrap_or_else(|| self.started_at.elapsed());
+        // Streams restart their timestamps when they reconnect: carry on
+        // from the last frame then, or from the wall clock if it is later
+        if now < self.last {
+            self.origin = (now, self.time(self.last).max(SystemTime::now()));
+        }
+
+        self.last = now;
+        self.last_seen = Instant::now();
+        self.live = frame.live;
+        self.time(now)
+    }
+
+    /// The time on the wall clock of the stream time `at`.
+    fn time(&self, at: Duration) -> SystemTime {
+        self.origin.1 + at.saturating_sub(self.origin.0)
+    }
+
+    /// The time on the wall clock of a live stream that has had no frame
+    /// for [`QUIET_AFTER`], as if it went on.
+    fn quiet_time(&self) -> Option<SystemTime> {
+        let quiet = self.last_seen.elapsed();
+        (self.live && quiet >= QUIET_AFTER).then(|| self.time(self.last) + quiet)
+    }
+}
+
+/// The start of the interval `time` falls into, counting from the epoch.
+fn interval_start(time: SystemTime, interval: Duration) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let interval = interval.as_secs().max(1);
+
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs() / interval * interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CameraConfig;
+    use crate::testing::{frame, person_standing_at};
+
+    fn line(start: [f32; 2], end: [f32; 2]) -> LineConfig {
+        LineConfig {
+            name: "line".to_string(),
+            start,
+            end,
+            labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn crossings_to_the_right_go_in() {
+        // Image y points down, so the right of a line going right is below it
+        let horizontal = line([0.0, 0.5], [1.0, 0.5]);
+        assert_eq!(
+            horizontal.crossing([0.5, 0.4], [0.5, 0.6]),
+            Some(CrossingDirection::In)
+        );
+        assert_eq!(
+            horizontal.crossing([0.5, 0.6], [0.5, 0.4]),
+            Some(CrossingDirection::Out)
+        );
+
+        // And the right of a line going down is to its left on screen
+        let vertical = line([0.5, 0.0], [0.5, 1.0]);
+        assert_eq!(
+            vertical.crossing([0.6, 0.5], [0.4, 0.5]),
+            Some(CrossingDirection::In)
+        );
+        assert_eq!(
+            vertical.crossing([0.4, 0.5], [0.6, 0.5]),
+            Some(CrossingDirection::Out)
+        );
+    }
+
+    #[test]
+    fn moves_along_or_short_of_a_line_do_not_cross() {
+        let line = line([0.0, 0.5], [1.0, 0.5]);
+
+        assert_eq!(line.crossing([0.2, 0.5], [0.8, 0.5]), None);
+        assert_eq!(line.crossing([0.5, 0.3], [0.5, 0.4]), None);
+        assert_eq!(line.crossing([0.5, 0.4], [0.5, 0.4]), None);
+        // Past the end of the segment
+        assert_eq!(line.crossing([1.5, 0.4], [1.5, 0.6]), None);
+    }
+
+    /// Feed the person of track 1 standing at `(50, y)` at `pts` seconds.
+    fn update(counters: &Counters, config: &Config, (pts, y): (u64, f32)) -> Vec<Event> {
+        counters.update(
+            config,
+            &frame(0, Duration::from_secs(pts)),
+            &[person_standing_at(y)],
+            &[],
+        )
+    }
+
+    fn crossings(events: &[Event]) -> Vec<(CrossingDirection, u64, u64)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::LineCrossing(event) => {
+                    Some((event.direction, event.count_in, event.count_out))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn counts(events: &[Event]) -> Vec<(u64, u64)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::LineCount(event) => Some((event.count_in, event.count_out)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn config() -> Config {
+        Config {
+            cameras: vec![CameraConfig {
+                id: "cam".to_string(),
+                source: "rtsp://camera".to_string(),
+                zones: Vec::new(),
+                lines: vec![line([0.0, 0.5], [1.0, 0.5])],
+                rtsp_mount_path: None,
+            }],
+            counting: CountingConfig {
+                interval: Duration::from_secs(60 * 60),
+                rolling_window: Duration::from_secs(60),
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn tracks_count_once_per_direction() {
+        let (config, counters) = (config(), Counters::default());
+
+        let directions = |at| {
+            crossings(&update(&counters, &config, at))
+                .into_iter()
+                .map(|(direction, _, _)| direction)
+                .collect::<Vec<_>>()
+        };
+        assert!(directions((0, 40.0)).is_empty());
+        assert_eq!(directions((1, 60.0)), [CrossingDirection::In]);
+        assert_eq!(directions((2, 40.0)), [CrossingDirection::Out]);
+        assert!(directions((3, 60.0)).is_empty());
+    }
+
+    #[test]
+    fn rolling_window_follows_frame_timestamps() {
+        let (config, counters) = (config(), Counters::default());
+
+        update(&counters, &config, (0, 40.0));
+        assert_eq!(
+            crossings(&update(&counters, &config, (1, 60.0))),
+            [(CrossingDirection::In, 1, 0)]
+        );
+        // Two minutes later in the stream, however fast it was read
+        assert_eq!(
+            crossings(&update(&counters, &config, (121, 40.0))),
+            [(CrossingDirection::Out, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn intervals_follow_frame_timestamps() {
+        let (config, counters) = (config(), Counters::default());
+
+        update(&counters, &config, (0, 40.0));
+        assert!(counts(&update(&counters, &config, (1, 60.0))).is_empty());
+        // An hour later in the stream, the first interval is over
+        assert_eq!(counts(&update(&counters, &config, (3601, 60.0))), [(1, 0)]);
+        assert!(counts(&counters.remove(&config, "cam")).is_empty());
+    }
+
+    #[test]
+    fn removed_cameras_report_their_last_interval() {
+        let (config, counters) = (config(), Counters::default());
+
+        // The crossings may straddle two intervals: add all their totals up
+        let mut events = Vec::new();
+        for at in [(0, 40.0), (1, 60.0), (2, 40.0)] {
+            events.extend(update(&counters, &config, at));
+        }
+        events.extend(counters.remove(&config, "cam"));
+        let totals = counts(&events)
+            .into_iter()
+            .fold((0, 0), |(a, b), (c, d)| (a + c, b + d));
+        assert_eq!(totals, (1, 1));
+    }
+}
@@ -1,231 +1,558 @@
 use anyhow::Context;
-use crossbeam::channel::bounded;
-use glib::object::Cast;
-use gst::prelude::*;
+use api::{ApiServer, Recent, RecentSink};
+use camera::{Camera, Cameras};
+use clap::Parser;
+use cli::{Cli, Command};
+use clips::{Recorder, Recorders};
+use config::{CameraConfig, Config, LogFormat, QueueConfig};
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use event::Event;
 use gstreamer as gst;
-use gstreamer::prelude::ElementExt;
 use gstreamer_app::AppSinkCallbacks;
-use gstreamer_video as gst_video;
-use image::{DynamicImage, ImageFormat, RgbImage};
-use ort::execution_providers::{CUDAExecutionProvider, CoreMLExecutionProvider};
-use std::fs::File;
-use std::{
-    env,
-    sync::atomic::{AtomicUsize, Ordering},
+use image::DynamicImage;
+use inference::{Analysis, Detector, Frame};
+use lines::Counters;
+use metrics::{MetricsServer, metrics};
+use sampler::FrameSampler;
+use scheduler::{FairQueue, Pushed};
+use sink::{Dispatcher, Inference};
+use std::path::Path;
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
 };
-use yolo_rs::model::YoloModelSession;
-use yolo_rs::{BoundingBox, image_to_yolo_input_tensor, inference};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use supervisor::{Control, Heartbeat, Liveness};
+use tracker::Trackers;
+use yolo_rs::BoundingBox;
+use zones::Zones;
+
+mod annotate;
+mod api;
+mod bench;
+mod camera;
+mod cli;
+mod clips;
+mod codec;
+mod config;
+mod crops;
+mod event;
+mod frame;
+mod inference;
+mod lines;
+mod metrics;
+mod mqtt;
+mod pipeline;
+mod probe;
+mod rtsp;
+mod sampler;
+mod scheduler;
+mod sink;
+mod source;
+mod store;
+mod supervisor;
+#[cfg(test)]
+mod testing;
+mod tracker;
+mod webhook;
+mod zones;
 
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+
+    if let Command::CheckConfig = cli.command {
+        let path = cli.config.context("check-config needs --config <FILE>")?;
+        return check_config(&Config::load(&path)?);
+    }
+
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    match cli.command {
+        Command::Run { sources } => {
+            let first_index = config.cameras.len();
+            config
+                .cameras
+                .extend(sources.iter().enumerate().map(|(index, argument)| {
+                    CameraConfig::from_argument(first_index + index, argument)
+                }));
+            config.validate()?;
+            init(&config)?;
+
+            run(Arc::new(config))
+        }
+        Command::DetectFile { path } => {
+            let is_image = image::ImageFormat::from_path(&path).is_ok();
+            config.cameras = vec![CameraConfig {
+                id: "file".to_string(),
+                source: path.to_string_lossy().into_owned(),
+                zones: Vec::new(),
+                lines: Vec::new(),
+                rtsp_mount_path: None,
+            }];
+            // The run ends with the file, so there is nothing to add cameras
+            // to or follow through the API
+            config.api.enabled = false;
+            config.validate()?;
+            init(&config)?;
+
+            if is_image {
+                detect_image(&Arc::new(config), &path)
+            } else {
+                run(Arc::new(config))
+            }
+        }
+        Command::Bench {
+            model,
+            width,
+            height,
+            warmup,
+            iterations,
+        } => {
+            init(&config)?;
+
+            let model_path = model.unwrap_or_else(|| config.model.path.clone());
+            bench::run(
+                &model_path,
+                config.model.version,
+                width,
+                height,
+                warmup,
+                iterations,
+            )
+        }
+        Command::Probe {
+            source,
+            timeout,
+            frames,
+        } => {
+            init_logging(&config);
+            gst::init()?;
+
+            probe::run(&source, timeout, frames)
+        }
+        Command::Query {
+            camera,
+            label,
+            since,
+            until,
+            min_confidence,
+            limit,
+            format,
+            database,
+        } => {
+            let path = database.unwrap_or_else(|| config.database.path.clone());
+            let detections = store::Store::open_read_only(&path)?.query(&store::Query {
+                camera_id: camera,
+                label,
+                since,
+                until,
+                min_confidence,
+                limit,
+            })?;
+
+            store::print(&detections, format)
+        }
+        Command::CheckConfig => unreachable!("handled above"),
+    }
+}
+
+/// Set up logging, GStreamer and ONNX runtime.
+fn init(config: &Config) -> anyhow::Result<()> {
+    init_logging(config);
 
     // Initialize GStreamer
     gst::init()?;
 
     // Initialize ONNX runtime
-    ort::init()
-        .with_execution_providers([
-            CUDAExecutionProvider::default().build(),
-            CoreMLExecutionProvider::default().build(),
-        ])
-        .with_telemetry(true)
-        .commit()
-        .expect("failed to initialize ONNX runtime");
-
-    // Check for RTSP stream URI argument
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <RTSP URL>", args[0]);
+    inference::init_runtime(config)?;
+
+    Ok(())
+}
+
+fn init_logging(config: &Config) {
+    // Keep stdout free for detection events
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(config.log_level())
+        .with_writer(std::io::stderr);
+    match config.logging.format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Pretty => subscriber.pretty().init(),
+    }
+}
+
+fn check_config(config: &Config) -> anyhow::Result<()> {
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("Configuration is valid.");
         return Ok(());
     }
-    let rtsp_url = &args[1];
 
-    let pipeline = gstreamer::Pipeline::new();
+    for problem in &problems {
+        println!("- {problem}");
+    }
+    anyhow::bail!("found {} problems in the configuration", problems.len())
+}
 
-    let rtspsrc_element = gst::ElementFactory::make("rtspsrc")
-        .property("location", rtsp_url)
-        .build()
-        .context("failed to create rtspsrc element")?;
+/// Run the detector once on a still image and print what it found.
+fn detect_image(config: &Arc<Config>, path: &Path) -> anyhow::Result<()> {
+    let image = image::open(path).with_context(|| format!("failed to open {}", path.display()))?;
 
-    let rtpjitterbuffer_element = gst::ElementFactory::make("rtpjitterbuffer")
-        .build()
-        .context("failed to create rtpjitterbuffer element")?;
+    std::fs::create_dir_all(&config.output.directory).with_context(|| {
+        format!(
+            "failed to create output directory {}",
+            config.output.directory.display()
+        )
+    })?;
+
+    let sinks = Dispatcher::start(sink::from_config(config)?, config.output.sink_queue_depth);
+
+    let detector = Detector::from_config(config)?;
+    let detections = detector.detect(&image)?;
+    let frame = Frame {
+        camera_id: Arc::from("file"),
+        id: 0,
+        pts: None,
+        image,
+        live: false,
+    };
+    let inference = Inference::new(config, frame, detections, None);
+
+    // Sinks may write to stdout, so the summary goes to stderr.
+    eprintln!(
+        "Found {} entities in {}",
+        inference.detections.len(),
+        path.display()
+    );
+    for (detection, object) in inference.detections.iter().zip(&inference.event.detections) {
+        let BoundingBox { x1, y1, x2, y2 } = detection.bounding_box;
+        let crop_path = match &object.crop_path {
+            Some(crop_path) => crop_path.display().to_string(),
+            None => "-".to_string(),
+        };
+        eprintln!(
+            "  {} {:.2} [{:.0}, {:.0}, {:.0}, {:.0}] -> {}",
+            detection.label, detection.confidence, x1, y1, x2, y2, crop_path
+        );
+    }
 
-    let rtph264depay_element = gst::ElementFactory::make("rtph264depay")
-        .property("wait-for-keyframe", true)
-        .property("request-keyframe", true)
-        .build()
-        .context("failed to create rtph264depay element")?;
+    sinks.dispatch(inference);
+    sinks.close();
 
-    let avdec_h264_element = gst::ElementFactory::make("avdec_h264")
-        .build()
-        .context("failed to create avdec_h264 element")?;
+    Ok(())
+}
 
-    let videoconvert_element = gst::ElementFactory::make("videoconvert")
-        .build()
-        .context("failed to create videoconvert element")?;
+fn run(config: Arc<Config>) -> anyhow::Result<()> {
+    let configured_cameras = config
+        .cameras
+        .iter()
+        .map(|camera| Ok((Camera::from_config(camera)?, camera.source.clone())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    std::fs::create_dir_all(&config.output.directory).with_context(|| {
+        format!(
+            "failed to create output directory {}",
+            config.output.directory.display()
+        )
+    })?;
 
-    let identity_element = gst::ElementFactory::make("identity")
-        .property("check-imperfect-offset", true)
-        .property("check-imperfect-timestamp", true)
-        .build()
-        .context("failed to create identity element")?;
+    let inference_queue = Arc::new(FairQueue::<Frame>::new(config.queue.depth));
+    let recent = Recent::new(config.api.recent_detections);
+    let mut outputs = sink::from_config(&config)?;
+    if config.api.enabled {
+        outputs.push(Box::new(RecentSink::new(recent.clone())));
+    }
+    let sinks = Arc::new(Dispatcher::start(outputs, config.output.sink_queue_depth));
+    let analysis = Arc::new(Analysis {
+        trackers: Trackers::default(),
+        zones: Zones::default(),
+        counters: Counters::default(),
+        recorders: Recorders::new(&config)?,
+    });
+    let metrics_server = config
+        .metrics
+        .enabled
+        .then(|| MetricsServer::start(&config.metrics, inference_queue.clone()))
+        .transpose()?;
+    let cameras = Cameras::new(
+        config.clone(),
+        inference_queue.clone(),
+        analysis.clone(),
+        &sinks,
+    );
+    let inference_workers = inference::spawn_workers(
+        config.clone(),
+        inference_queue.clone(),
+        analysis.clone(),
+        sinks.clone(),
+        cameras.clone(),
+    );
+    let (stop_flushing, count_flusher) =
+        spawn_count_flusher(config.clone(), analysis.clone(), sinks.clone());
+
+    for (camera, source) in configured_cameras {
+        cameras.start(camera, source)?;
+    }
+    let api_server = config
+        .api
+        .enabled
+        .then(|| ApiServer::start(&config.api, cameras.clone(), recent.clone()))
+        .transpose()?;
+    install_signal_handler(cameras.clone())?;
+
+    // Cameras may be added through the API, so keep serving it even once
+    // every camera ended
+    cameras.wait(config.api.enabled);
+    if let Some(api_server) = api_server {
+        api_server.stop();
+    }
+    let failed = cameras.stop();
+
+    for lane in inference_queue.stats() {
+        tracing::info!(
+            "Camera {}: {} frames dropped, {} frames left to infer",
+            lane.key,
+            lane.dropped,
+            lane.depth
+        );
+    }
+
+    // Let the inference workers finish the queued frames, for at most the
+    // drain timeout when interrupted
+    inference_queue.close();
+    let deadline = Instant::now() + config.queue.drain_timeout;
+    let mut discarded = 0;
+    while cameras.is_shutting_down() && inference_workers.iter().any(|worker| !worker.is_finished())
+    {
+        if Instant::now() >= deadline {
+            discarded = inference_queue.discard();
+            tracing::warn!(
+                "Discarding {} frames left to infer after {:?}",
+                discarded,
+                config.queue.drain_timeout
+            );
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    for worker in inference_workers {
+        worker.join().expect("failed to join thread");
+    }
+    drop(stop_flushing);
+    count_flusher.join().expect("failed to join thread");
+
+    // Then end the tracks that are still alive and let the sinks deliver
+    // what the workers produced
+    analysis.recorders.finish();
+    let track_events = analysis.trackers.finish();
+    let zone_events = analysis.zones.end_tracks(&config, &track_events);
+    for event in track_events {
+        sinks.dispatch_event(Event::Track(event));
+    }
+    for event in zone_events {
+        sinks.dispatch_event(Event::Zone(event));
+    }
+    for event in analysis.counters.finish(&config) {
+        sinks.dispatch_event(event);
+    }
+    Arc::into_inner(sinks)
+        .expect("inference workers have exited")
+        .close();
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.stop();
+    }
 
-    let frame_counter = AtomicUsize::new(0);
+    if let Some(failure) = cameras.failure() {
+        anyhow::bail!("{failure}");
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("cameras {} failed", failed.join(", "));
+    }
+    if discarded > 0 {
+        anyhow::bail!("shut down before inferring {discarded} frames");
+    }
 
-    let (inferrence_queue_sender, inferrence_queue_receiver) = bounded::<(usize, DynamicImage)>(30);
+    Ok(())
+}
+
+/// Report the interval totals of the live cameras that went quiet every
+/// second, until `stop` is dropped.
+fn spawn_count_flusher(
+    config: Arc<Config>,
+    analysis: Arc<Analysis>,
+    sinks: Arc<Dispatcher>,
+) -> (Sender<()>, JoinHandle<()>) {
+    let (stop, stopped) = channel::bounded::<()>(0);
+
+    let thread = std::thread::Builder::new()
+        .name("count-flusher".to_string())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_secs(1))
+            {
+                for event in analysis.counters.flush_quiet(&config) {
+                    sinks.dispatch_event(event);
+                }
+            }
+        })
+        .expect("failed to spawn count flusher");
 
-    let inference_worker = std::thread::spawn(|| {
-        let yolo_model = YoloModelSession::from_filename_v8("models/yolo11x.onnx")
-            .expect("failed to load YOLO model");
+    (stop, thread)
+}
 
-        for (frame_id, frame) in inferrence_queue_receiver {
-            tracing::info!("Inferring frame {}", frame_id);
-            let now = std::time::Instant::now();
+/// Shut the cameras down on the first SIGINT or SIGTERM, and exit right
+/// away on the second.
+fn install_signal_handler(cameras: Arc<Cameras>) -> anyhow::Result<()> {
+    let mut interrupted = false;
 
-            let yolo_input = image_to_yolo_input_tensor(&frame);
-            let yolo_output =
-                inference(&yolo_model, yolo_input.view()).expect("failed to run inference");
+    ctrlc::set_handler(move || {
+        if interrupted {
+            tracing::warn!("Interrupted again, exiting without draining");
+            std::process::exit(130);
+        }
 
-            tracing::info!(
-                "Found {} entities, elapsed: {:?}",
-                yolo_output.len(),
-                now.elapsed()
-            );
+        interrupted = true;
+        tracing::info!("Shutting down, interrupt again to exit right away");
+        cameras.shutdown();
+    })
+    .context("failed to install signal handler")
+}
 
-            // extract the entity to few pictures
-            for entity in yolo_output {
-                let BoundingBox { x1, x2, y1, y2 } = entity.bounding_box;
-                let label = entity.label;
-                let confidence = entity.confidence;
-
-                let cropped_image =
-                    frame.crop_imm(x1 as _, y1 as _, (x2 - x1) as u32, (y2 - y1) as u32);
-
-                // save the image to "frame-<counter>-<label>-<confidence>.png"
-                let mut file = File::create(format!(
-                    "frame_{}_{}_{:.2}.png",
-                    frame_id, label, confidence
-                ))
-                .expect("expect a valid file");
-                cropped_image
-                    .write_to(&mut file, ImageFormat::Png)
-                    .expect("expect a valid image");
+/// Stream one camera into the shared inference queue, and its H.264 stream
+/// into `recorder`, until it ends.
+fn run_camera(
+    config: &Config,
+    camera: &Camera,
+    inference_queue: Arc<FairQueue<Frame>>,
+    recorder: Option<Arc<Recorder>>,
+    control: &Control,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        "Reading frames of {} from {}",
+        camera.id,
+        camera.source.describe()
+    );
+
+    let frame_counter = Arc::new(AtomicUsize::new(0));
+    let mut sessions = 0;
+
+    supervisor::supervise(
+        camera.source.as_ref(),
+        config.video.accept_yuv,
+        &config.reconnect,
+        control,
+        |heartbeat, liveness| {
+            // Every session but the first is a reconnection
+            if sessions > 0 {
+                metrics().reconnected(&camera.id);
             }
-        }
-    });
+            sessions += 1;
+
+            pipeline::Callbacks {
+                frames: appsink_callbacks(
+                    FrameSampler::new(config.sampling.rate),
+                    config.queue.clone(),
+                    liveness,
+                    camera.id.clone(),
+                    frame_counter.clone(),
+                    inference_queue.clone(),
+                    heartbeat,
+                ),
+                encoded: recorder.clone().map(recorder_callbacks),
+            }
+        },
+    )
+}
 
-    let appsink_callback = AppSinkCallbacks::builder()
+fn recorder_callbacks(recorder: Arc<Recorder>) -> AppSinkCallbacks {
+    AppSinkCallbacks::builder()
         .new_sample(move |sink| {
+            let sample = sink.pull_sample().map_err(|_| gst::FlowError::Error)?;
+            recorder.push(&sample);
+
+            Ok(gst::FlowSuccess::Ok)
+        })
+        .build()
+}
+
+fn appsink_callbacks(
+    mut sampler: FrameSampler,
+    queue: QueueConfig,
+    liveness: Liveness,
+    camera_id: Arc<str>,
+    frame_counter: Arc<AtomicUsize>,
+    inference_queue: Arc<FairQueue<Frame>>,
+    heartbeat: Heartbeat,
+) -> AppSinkCallbacks {
+    AppSinkCallbacks::builder()
+        .new_sample(move |sink| {
+            heartbeat.beat();
+            metrics().frame_decoded(&camera_id);
+
             let sample = match sink.pull_sample() {
                 Ok(sample) => sample,
                 Err(_) => return Err(gst::FlowError::Error),
             };
 
-            // Extract the buffer and caps (metadata)
-            let buffer = sample.buffer().unwrap();
-            let caps = sample.caps().unwrap();
-            let video_info = gst_video::VideoInfo::from_caps(caps).unwrap();
-
-            // Convert the buffer to a readable format
-            let map = buffer.map_readable().unwrap();
+            let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
 
             // Increment the frame counter
             let counter = frame_counter.fetch_add(1, Ordering::Relaxed);
 
-            // Save frame as PNG every second (assuming 1 frame per second)
-            if counter % 30 == 0 {
-                // Adjust based on your stream's FPS
-                let width = video_info.width() as usize;
-                let height = video_info.height() as usize;
-
-                // Extract the frame data
-                let frame_data = map.as_slice();
-
-                let frame = RgbImage::from_raw(width as u32, height as u32, frame_data.to_vec())
-                    .expect("expect a valid image");
-                let dynamic_image = DynamicImage::ImageRgb8(frame);
-
-                inferrence_queue_sender
-                    .send((counter, dynamic_image))
-                    .expect("failed to send frame to inferrence queue");
+            // Time the frame on the running time, which stays monotonic across
+            // segments, and fall back to the raw PTS.
+            let pts = buffer.pts();
+            let running_time = sample
+                .segment()
+                .and_then(|segment| segment.downcast_ref::<gst::ClockTime>())
+                .and_then(|segment| pts.and_then(|pts| segment.to_running_time(pts)))
+                .or(pts);
+
+            // Only infer the frames that keep up with the sampling rate
+            if sampler.should_sample(running_time.map(clock_time_to_duration)) {
+                metrics().frame_sampled(&camera_id);
+                let image = match frame::sample_to_rgb_image(&sample) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        tracing::warn!("Skipping frame {} of {}: {:#}", counter, camera_id, e);
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                };
+
+                let live = liveness.is_live();
+                let policy = queue.policy_for(live);
+                let frame = Frame {
+                    camera_id: camera_id.clone(),
+                    id: counter,
+                    pts: pts.map(clock_time_to_duration),
+                    image: DynamicImage::ImageRgb8(image),
+                    live,
+                };
+
+                match inference_queue.push(&camera_id, frame, policy) {
+                    Ok(Pushed::Queued) => (),
+                    Ok(Pushed::Dropped { total_dropped }) => {
+                        if total_dropped == 1 || total_dropped % 100 == 0 {
+                            tracing::warn!(
+                                "Inference is falling behind on {}: {} frames dropped ({:?})",
+                                camera_id,
+                                total_dropped,
+                                policy
+                            );
+                        }
+                    }
+                    Err(_) => return Err(gst::FlowError::Eos),
+                }
             }
 
             Ok(gst::FlowSuccess::Ok)
         })
-        .build();
-
-    let appsink_element = gstreamer_app::AppSink::builder()
-        .name("appsink")
-        .sync(true)
-        .callbacks(appsink_callback)
-        .caps(
-            &gst::Caps::builder("video/x-raw")
-                .field("format", "RGB")
-                .build(),
-        )
         .build()
-        .upcast();
-
-    pipeline.add_many([
-        &rtspsrc_element,
-        &rtpjitterbuffer_element,
-        &rtph264depay_element,
-        &avdec_h264_element,
-        &videoconvert_element,
-        &identity_element,
-        &appsink_element,
-    ])?;
-
-    let rtpjitterbuffer_element_clone = rtpjitterbuffer_element.clone();
-    rtspsrc_element.connect_pad_added(move |_, src_pad| {
-        let sink_pad = rtpjitterbuffer_element_clone.static_pad("sink").unwrap();
-        if !sink_pad.is_linked() {
-            match src_pad.link(&sink_pad) {
-                Ok(_) => tracing::info!("Successfully linked pads"),
-                Err(err) => tracing::warn!("Failed to link pads: {:?}", err),
-            }
-        }
-    });
-
-    // link elements
-    gst::Element::link_many([
-        &rtpjitterbuffer_element,
-        &rtph264depay_element,
-        &avdec_h264_element,
-        &videoconvert_element,
-        &identity_element,
-        &appsink_element,
-    ])?;
-
-    // Start the pipeline
-    pipeline
-        .set_state(gst::State::Playing)
-        .context("failed to start pipeline")?;
-
-    // Wait until error or EOS
-    let bus = pipeline.bus().context("failed to get bus")?;
-    for msg in bus.iter_timed(gst::ClockTime::NONE) {
-        match msg.view() {
-            gst::MessageView::Eos(..) => break,
-            gst::MessageView::Error(err) => {
-                eprintln!(
-                    "Error from {}: {}",
-                    err.src().map(|s| s.path_string()).unwrap_or("<?>".into()),
-                    err.error()
-                );
-                break;
-            }
-            _ => (),
-        }
-    }
-
-    // Shutdown pipeline
-    pipeline
-        .set_state(gst::State::Null)
-        .context("failed to stop pipeline")?;
-
-    inference_worker.join().expect("failed to join thread");
+}
 
-    Ok(())
+fn clock_time_to_duration(time: gst::ClockTime) -> Duration {
+    Duration::from_nanos(time.nseconds())
 }
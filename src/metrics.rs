@@ -0,0 +1,357 @@
+use crate::inference::Frame;
+use crate::scheduler::FairQueue;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the inference latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve the Prometheus metrics on `http://<address>/metrics`.
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "0.0.0.0:9090".to_string(),
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// The metrics of the process, recorded from wherever they happen.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    cameras: Mutex<BTreeMap<String, CameraMetrics>>,
+    /// Keyed by camera and label.
+    detections: Mutex<BTreeMap<(String, String), u64>>,
+    sinks: Mutex<BTreeMap<&'static str, SinkMetrics>>,
+}
+
+#[derive(Default)]
+struct CameraMetrics {
+    frames_decoded: u64,
+    frames_sampled: u64,
+    reconnects: u64,
+    last_frame: Option<Instant>,
+    inference_seconds: Histogram,
+}
+
+#[derive(Default)]
+struct SinkMetrics {
+    failures: u64,
+    dropped: u64,
+}
+
+struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulated.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    fn camera<T>(&self, camera_id: &str, update: impl FnOnce(&mut CameraMetrics) -> T) -> T {
+        let mut cameras = self.cameras.lock().unwrap();
+        match cameras.get_mut(camera_id) {
+            Some(camera) => update(camera),
+            None => update(cameras.entry(camera_id.to_string()).or_default()),
+        }
+    }
+
+    /// A frame came out of the decoder.
+    pub fn frame_decoded(&self, camera_id: &str) {
+        self.camera(camera_id, |camera| {
+            camera.frames_decoded += 1;
+            camera.last_frame = Some(Instant::now());
+        });
+    }
+
+    /// A frame was picked for inference.
+    pub fn frame_sampled(&self, camera_id: &str) {
+        self.camera(camera_id, |camera| camera.frames_sampled += 1);
+    }
+
+    pub fn reconnected(&self, camera_id: &str) {
+        self.camera(camera_id, |camera| camera.reconnects += 1);
+    }
+
+    pub fn inferred(&self, camera_id: &str, elapsed: Duration, labels: &[&str]) {
+        self.camera(camera_id, |camera| {
+            camera.inference_seconds.observe(elapsed.as_secs_f64())
+        });
+
+        let mut detections = self.detections.lock().unwrap();
+        for label in labels {
+            *detections
+                .entry((camera_id.to_string(), label.to_string()))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn sink_failed(&self, sink: &'static str) {
+        self.sinks.lock().unwrap().entry(sink).or_default().failures += 1;
+    }
+
+    pub fn sink_dropped(&self, sink: &'static str) {
+        self.sinks.lock().unwrap().entry(sink).or_default().dropped += 1;
+    }
+
+    /// Everything in the Prometheus text format.
+    fn render(&self, queue: &FairQueue<Frame>) -> String {
+        let mut output = String::new();
+        let cameras = self.cameras.lock().unwrap();
+
+        let counters: [(&str, &str, fn(&CameraMetrics) -> u64); 3] = [
+            (
+                "stream_yolo_frames_decoded_total",
+                "Frames decoded per camera; rate() gives the decoded fps.",
+                |camera| camera.frames_decoded,
+            ),
+            (
+                "stream_yolo_frames_sampled_total",
+                "Frames picked for inference per camera; rate() gives the sampled fps.",
+                |camera| camera.frames_sampled,
+            ),
+            (
+                "stream_yolo_reconnects_total",
+                "Pipeline restarts per camera.",
+                |camera| camera.reconnects,
+            ),
+        ];
+        for (name, help, value) in counters {
+            header(&mut output, name, help, "counter");
+            for (camera_id, camera) in cameras.iter() {
+                sample(&mut output, name, &[("camera", camera_id)], value(camera));
+            }
+        }
+
+        let name = "stream_yolo_last_frame_age_seconds";
+        header(
+            &mut output,
+            name,
+            "Time since the last decoded frame per camera.",
+            "gauge",
+        );
+        for (camera_id, camera) in cameras.iter() {
+            if let Some(last_frame) = camera.last_frame {
+                sample(
+                    &mut output,
+                    name,
+                    &[("camera", camera_id)],
+                    last_frame.elapsed().as_secs_f64(),
+                );
+            }
+        }
+
+        let lanes = queue.stats();
+        let name = "stream_yolo_queue_depth";
+        header(
+            &mut output,
+            name,
+            "Frames waiting for inference per camera.",
+            "gauge",
+        );
+        for lane in &lanes {
+            sample(&mut output, name, &[("camera", &lane.key)], lane.depth);
+        }
+        let name = "stream_yolo_frames_dropped_total";
+        header(
+            &mut output,
+            name,
+            "Sampled frames dropped before inference per camera.",
+            "counter",
+        );
+        for lane in &lanes {
+            sample(&mut output, name, &[("camera", &lane.key)], lane.dropped);
+        }
+
+        let name = "stream_yolo_inference_duration_seconds";
+        header(
+            &mut output,
+            name,
+            "Time to run the model on a frame per camera.",
+            "histogram",
+        );
+        for (camera_id, camera) in cameras.iter() {
+            let histogram = &camera.inference_seconds;
+            let mut cumulated = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.counts) {
+                cumulated += count;
+                sample(
+                    &mut output,
+                    &format!("{name}_bucket"),
+                    &[("camera", camera_id), ("le", &bound.to_string())],
+                    cumulated,
+                );
+            }
+            sample(
+                &mut output,
+                &format!("{name}_bucket"),
+                &[("camera", camera_id), ("le", "+Inf")],
+                histogram.count,
+            );
+            sample(
+                &mut output,
+                &format!("{name}_sum"),
+                &[("camera", camera_id)],
+                histogram.sum,
+            );
+            sample(
+                &mut output,
+                &format!("{name}_count"),
+                &[("camera", camera_id)],
+                histogram.count,
+            );
+        }
+        drop(cameras);
+
+        let name = "stream_yolo_detections_total";
+        header(
+            &mut output,
+            name,
+            "Detected objects per camera and label.",
+            "counter",
+        );
+        for ((camera_id, label), count) in self.detections.lock().unwrap().iter() {
+            sample(
+                &mut output,
+                name,
+                &[("camera", camera_id), ("label", label)],
+                count,
+            );
+        }
+
+        let sinks = self.sinks.lock().unwrap();
+        let name = "stream_yolo_sink_failures_total";
+        header(
+            &mut output,
+            name,
+            "Events a sink failed to handle.",
+            "counter",
+        );
+        for (sink, metrics) in sinks.iter() {
+            sample(&mut output, name, &[("sink", sink)], metrics.failures);
+        }
+        let name = "stream_yolo_sink_dropped_total";
+        header(
+            &mut output,
+            name,
+            "Events dropped because a sink was falling behind.",
+            "counter",
+        );
+        for (sink, metrics) in sinks.iter() {
+            sample(&mut output, name, &[("sink", sink)], metrics.dropped);
+        }
+
+        output
+    }
+}
+
+fn header(output: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {kind}");
+}
+
+fn sample(output: &mut String, name: &str, labels: &[(&str, &str)], value: impl ToString) {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let _ = writeln!(output, "{name}{{{labels}}} {}", value.to_string());
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `/metrics` on its own thread until [`MetricsServer::stop`].
+pub struct MetricsServer {
+    server: Arc<tiny_http::Server>,
+    thread: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub fn start(config: &MetricsConfig, queue: Arc<FairQueue<Frame>>) -> anyhow::Result<Self> {
+        let server = tiny_http::Server::http(&config.address)
+            .map_err(|err| anyhow::anyhow!("{err}"))
+            .with_context(|| format!("failed to listen on {}", config.address))?;
+        let server = Arc::new(server);
+        tracing::info!("Serving metrics on http://{}/metrics", config.address);
+
+        let thread = std::thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn({
+                let server = server.clone();
+                move || {
+                    for request in server.incoming_requests() {
+                        let path = request.url().split('?').next().unwrap_or_default();
+                        let response = if path == "/metrics" {
+                            let content_type = tiny_http::Header::from_bytes(
+                                &b"Content-Type"[..],
+                                &b"text/plain; version=0.0.4; charset=utf-8"[..],
+                            )
+                            .expect("valid header");
+                            tiny_http::Response::from_string(metrics().render(&queue))
+                                .with_header(content_type)
+                        } else {
+                            tiny_http::Response::from_string("not found").with_status_code(404)
+                        };
+
+                        if let Err(err) = request.respond(response) {
+                            tracing::debug!("Failed to answer a metrics request: {}", err);
+                        }
+                    }
+                }
+            })
+            .context("failed to spawn metrics thread")?;
+
+        Ok(Self { server, thread })
+    }
+
+    pub fn stop(self) {
+        self.server.unblock();
+        if self.thread.join().is_err() {
+            tracing::error!("Metrics server panicked");
+        }
+    }
+}
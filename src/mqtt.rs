@@ -0,0 +1,315 @@
+use crate::config::Config;
+use crate::event::{self, DetectionEvent};
+use crate::inference;
+use crate::metrics::metrics;
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use image::ImageFormat;
+use rumqttc::{Client, ConnectionError, Event, MqttOptions, Outgoing, QoS, Transport};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Packets larger than the MQTT client's 10 KiB default are rejected, which
+/// thumbnails and busy frames easily exceed.
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Names the persistent session the broker keeps across reconnections,
+    /// so it must be unique among its clients: instances sharing an ID take
+    /// over each other's session. When unset, a random ID and a clean session
+    /// are used.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// CA certificate (PEM) of the broker; connects over TLS when set.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key (PEM) for mutual TLS.
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    /// Topic of the detection events. Supports the `{camera}` and `{label}`
+    /// placeholders; one message is published per label found on a frame.
+    pub topic: String,
+    /// 0, 1 or 2.
+    pub qos: u8,
+    /// Retain the messages, so that subscribers immediately get the last
+    /// sighting of every label on every camera.
+    pub retain: bool,
+    /// Topic of the track, zone, line and error events. Supports the
+    /// `{camera}`, `{label}` and `{type}` (`track`, `zone`, `line_crossing`,
+    /// `line_count` or `error`) placeholders.
+    pub event_topic: String,
+    /// Topic of a JPEG thumbnail of the most confident detection of each
+    /// label, with the same placeholders as `topic`. Disabled when unset.
+    pub thumbnail_topic: Option<String>,
+    /// Longest side of the thumbnails, in pixels.
+    pub thumbnail_size: u32,
+    #[serde(with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// Number of messages buffered while the broker is unreachable; further
+    /// messages are dropped until it is back.
+    pub offline_buffer: usize,
+}
+
+impl MqttConfig {
+    pub const PLACEHOLDERS: &[&str] = &["camera", "label"];
+    pub const EVENT_PLACEHOLDERS: &[&str] = &["camera", "label", "type"];
+
+    pub fn qos(&self) -> Option<QoS> {
+        match self.qos {
+            0 => Some(QoS::AtMostOnce),
+            1 => Some(QoS::AtLeastOnce),
+            2 => Some(QoS::ExactlyOnce),
+            _ => None,
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: None,
+            username: None,
+            password: None,
+            ca_file: None,
+            client_cert_file: None,
+            client_key_file: None,
+            topic: "cameras/{camera}/detections/{label}".to_string(),
+            qos: 1,
+            retain: false,
+            event_topic: "cameras/{camera}/{type}/{label}".to_string(),
+            thumbnail_topic: None,
+            thumbnail_size: 320,
+            keep_alive: Duration::from_secs(30),
+            offline_buffer: 1000,
+        }
+    }
+}
+
+/// Publishes detection events, and optionally thumbnails, to an MQTT broker.
+pub struct MqttSink {
+    config: Arc<Config>,
+    qos: QoS,
+    client: Client,
+    stopping: Arc<AtomicBool>,
+    connection_thread: Option<JoinHandle<()>>,
+    /// Messages dropped because the offline buffer was full.
+    dropped: u64,
+}
+
+impl MqttSink {
+    /// Create the client and start the thread that drives its connection.
+    ///
+    /// The broker does not have to be reachable yet: up to
+    /// `mqtt.offline_buffer` messages are buffered and the connection is
+    /// retried in the background.
+    pub fn connect(config: Arc<Config>) -> anyhow::Result<Self> {
+        let mqtt = &config.mqtt;
+        let qos = mqtt.qos().context("mqtt.qos must be 0, 1 or 2")?;
+
+        let (client_id, clean_session) = match &mqtt.client_id {
+            Some(client_id) => (client_id.clone(), false),
+            None => (format!("stream-yolo-{:016x}", fastrand::u64(..)), true),
+        };
+        let mut options = MqttOptions::new(client_id, &mqtt.host, mqtt.port);
+        options
+            .set_keep_alive(mqtt.keep_alive)
+            .set_clean_session(clean_session)
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        if let Some(username) = &mqtt.username {
+            options.set_credentials(username, mqtt.password.as_deref().unwrap_or_default());
+        }
+        if let Some(ca_file) = &mqtt.ca_file {
+            options.set_transport(tls_transport(mqtt, ca_file)?);
+        }
+
+        let (client, mut connection) = Client::new(options, mqtt.offline_buffer.max(1));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let connection_thread = {
+            let stopping = stopping.clone();
+            let broker = format!("{}:{}", mqtt.host, mqtt.port);
+
+            std::thread::Builder::new()
+                .name("mqtt".to_string())
+                .spawn(move || {
+                    let mut connected = true;
+                    for notification in connection.iter() {
+                        match notification {
+                            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                            Ok(_) => connected = true,
+                            Err(ConnectionError::RequestsDone) => break,
+                            Err(_) if stopping.load(Ordering::Relaxed) => break,
+                            Err(err) => {
+                                if connected {
+                                    tracing::warn!(
+                                        "MQTT connection to {} failed, buffering: {}",
+                                        broker,
+                                        err
+                                    );
+                                }
+                                connected = false;
+                                std::thread::sleep(Duration::from_secs(1));
+                            }
+                        }
+                    }
+                })
+                .context("failed to spawn MQTT connection thread")?
+        };
+
+        Ok(Self {
+            config,
+            qos,
+            client,
+            stopping,
+            connection_thread: Some(connection_thread),
+            dropped: 0,
+        })
+    }
+
+    /// Queue a message without waiting, so that a full offline buffer drops
+    /// it rather than holding up this sink and the inference feeding it.
+    fn publish(&mut self, topic: &str, retain: bool, payload: Vec<u8>) {
+        if self
+            .client
+            .try_publish(topic, self.qos, retain, payload)
+            .is_ok()
+        {
+            return;
+        }
+
+        metrics().sink_dropped("mqtt");
+        self.dropped += 1;
+        if self.dropped == 1 || self.dropped % 100 == 0 {
+            tracing::warn!(
+                "MQTT buffer is full, {} messages dropped so far",
+                self.dropped
+            );
+        }
+    }
+
+    fn topic(template: &str, camera_id: &str, label: &str) -> String {
+        template
+            .replace("{camera}", camera_id)
+            .replace("{label}", label)
+    }
+
+    fn thumbnail(&self, inference: &Inference, label: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(best) = inference
+            .detections
+            .iter()
+            .filter(|detection| detection.label == label)
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+        else {
+            return Ok(None);
+        };
+
+        let size = self.config.mqtt.thumbnail_size;
+        let thumbnail = inference::crop(&inference.frame.image, &best.bounding_box)
+            .thumbnail(size, size)
+            .into_rgb8();
+
+        let mut jpeg = Cursor::new(Vec::new());
+        thumbnail
+            .write_to(&mut jpeg, ImageFormat::Jpeg)
+            .context("failed to encode thumbnail")?;
+
+        Ok(Some(jpeg.into_inner()))
+    }
+}
+
+impl DetectionSink for MqttSink {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        let config = self.config.clone();
+        let mqtt = &config.mqtt;
+        let event = &inference.event;
+        let labels = event
+            .detections
+            .iter()
+            .map(|object| object.label.as_str())
+            .collect::<BTreeSet<_>>();
+
+        for label in labels {
+            let message = DetectionEvent {
+                detections: event
+                    .detections
+                    .iter()
+                    .filter(|object| object.label == label)
+                    .cloned()
+                    .collect(),
+                ..event.clone()
+            };
+            let payload = serde_json::to_vec(&message).context("failed to serialize event")?;
+            let topic = Self::topic(&mqtt.topic, &event.camera_id, label);
+            self.publish(&topic, mqtt.retain, payload);
+
+            if let Some(thumbnail_topic) = &mqtt.thumbnail_topic
+                && let Some(thumbnail) = self.thumbnail(inference, label)?
+            {
+                let topic = Self::topic(thumbnail_topic, &event.camera_id, label);
+                self.publish(&topic, mqtt.retain, thumbnail);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &event::Event) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(&event.record()).context("failed to serialize event")?;
+        let topic = Self::topic(
+            &self.config.mqtt.event_topic,
+            event.camera_id(),
+            event.label(),
+        )
+        .replace("{type}", event.type_name());
+        self.publish(&topic, false, payload);
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.stopping.store(true, Ordering::Relaxed);
+        // Queued behind the pending publishes, so those are sent first. A
+        // full buffer means the broker is unreachable: leave the connection
+        // thread behind rather than wait for it.
+        if self.client.try_disconnect().is_err() {
+            tracing::warn!("MQTT buffer is full, dropping the messages left in it");
+            return Ok(());
+        }
+
+        if let Some(thread) = self.connection_thread.take() {
+            thread.join().ok();
+        }
+
+        Ok(())
+    }
+}
+
+fn tls_transport(config: &MqttConfig, ca_file: &Path) -> anyhow::Result<Transport> {
+    let read = |path: &Path| {
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+    };
+
+    let client_auth = match (&config.client_cert_file, &config.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => anyhow::bail!("mqtt.client_cert_file and mqtt.client_key_file must be set together"),
+    };
+
+    Ok(Transport::tls(read(ca_file)?, client_auth, None))
+}
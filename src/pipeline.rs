@@ -0,0 +1,128 @@
+use crate::frame;
+use crate::source::FrameSource;
+use anyhow::Context;
+use glib::object::Cast;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app::AppSinkCallbacks;
+use gstreamer_video as gst_video;
+
+/// What the appsinks of a pipeline are fed to.
+pub struct Callbacks {
+    /// The decoded frames.
+    pub frames: AppSinkCallbacks,
+    /// The H.264 stream before decoding, as byte-stream access units, for
+    /// the sources that have one.
+    pub encoded: Option<AppSinkCallbacks>,
+}
+
+/// Build `source → videoconvert → identity → appsink` with the frame
+/// callbacks installed on the appsink and, with encoded callbacks, a
+/// `queue → h264parse → appsink` branch the source may feed its H.264 stream
+/// into.
+///
+/// With `accept_yuv`, the appsink also takes NV12 and I420 so that
+/// `videoconvert` can pass decoded frames through; see
+/// [`frame::sample_to_rgb_image`]. The appsink only follows the clock once
+/// the pipeline is known to be live; see [`set_live`].
+pub fn build(
+    source: &dyn FrameSource,
+    accept_yuv: bool,
+    callbacks: Callbacks,
+) -> anyhow::Result<gst::Pipeline> {
+    let pipeline = gst::Pipeline::new();
+
+    let videoconvert_element = gst::ElementFactory::make("videoconvert")
+        .build()
+        .context("failed to create videoconvert element")?;
+
+    let identity_element = gst::ElementFactory::make("identity")
+        .property("check-imperfect-offset", true)
+        .property("check-imperfect-timestamp", true)
+        .build()
+        .context("failed to create identity element")?;
+
+    let appsink_element = gstreamer_app::AppSink::builder()
+        .name("appsink")
+        .sync(false)
+        .callbacks(callbacks.frames)
+        .caps(&appsink_caps(accept_yuv))
+        .build()
+        .upcast();
+
+    pipeline.add_many([&videoconvert_element, &identity_element, &appsink_element])?;
+
+    // link elements
+    gst::Element::link_many([&videoconvert_element, &identity_element, &appsink_element])?;
+
+    let encoded = callbacks
+        .encoded
+        .map(|callbacks| build_encoded_branch(&pipeline, callbacks))
+        .transpose()?;
+    source.attach(&pipeline, &videoconvert_element, encoded.as_ref())?;
+
+    Ok(pipeline)
+}
+
+/// Make the appsink follow the clock if the pipeline is live; offline
+/// pipelines are consumed as fast as inference allows.
+pub fn set_live(pipeline: &gst::Pipeline, live: bool) {
+    if let Some(appsink_element) = pipeline.by_name("appsink") {
+        appsink_element.set_property("sync", live);
+    }
+}
+
+/// Add `queue → h264parse → appsink` and return its first element.
+///
+/// The queue must not leak: a single lost access unit corrupts the rest of
+/// its GOP in the clips. Its consumer only buffers the access units, so the
+/// default bounds of the queue are enough to absorb its hiccups.
+fn build_encoded_branch(
+    pipeline: &gst::Pipeline,
+    callbacks: AppSinkCallbacks,
+) -> anyhow::Result<gst::Element> {
+    let queue_element = gst::ElementFactory::make("queue")
+        .build()
+        .context("failed to create queue element")?;
+
+    // Repeat SPS and PPS before every keyframe, so that any GOP decodes on
+    // its own
+    let h264parse_element = gst::ElementFactory::make("h264parse")
+        .property("config-interval", -1i32)
+        .build()
+        .context("failed to create h264parse element")?;
+
+    // Sources that cannot feed this branch leave it unlinked, so it must
+    // not hold up the state changes of the pipeline
+    let appsink_element = gstreamer_app::AppSink::builder()
+        .name("encoded")
+        .sync(false)
+        .async_(false)
+        .callbacks(callbacks)
+        .caps(
+            &gst::Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build(),
+        )
+        .build()
+        .upcast();
+
+    let branch = [&queue_element, &h264parse_element, &appsink_element];
+    pipeline.add_many(branch)?;
+    gst::Element::link_many(branch)?;
+
+    Ok(queue_element)
+}
+
+fn appsink_caps(accept_yuv: bool) -> gst::Caps {
+    if accept_yuv {
+        gst_video::VideoCapsBuilder::new()
+            .format_list(frame::YUV_AND_RGB_FORMATS.iter().copied())
+            .build()
+    } else {
+        gst_video::VideoCapsBuilder::new()
+            .format(gst_video::VideoFormat::Rgb)
+            .build()
+    }
+}
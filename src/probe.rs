@@ -0,0 +1,178 @@
+use crate::pipeline;
+use crate::source;
+use anyhow::{Context, bail};
+use crossbeam::channel::{RecvTimeoutError, bounded};
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app::AppSinkCallbacks;
+use gstreamer_video as gst_video;
+use std::time::{Duration, Instant};
+
+/// What the appsink saw of one decoded frame.
+struct ProbedFrame {
+    pts: Option<gst::ClockTime>,
+    video_info: gst_video::VideoInfo,
+}
+
+/// Play `source` until `frames` frames arrived (or `timeout` elapsed) and
+/// print what was negotiated.
+pub fn run(source: &str, timeout: Duration, frames: usize) -> anyhow::Result<()> {
+    let source = source::from_argument(source)?;
+    println!("Source:      {}", source.describe());
+
+    let (frame_sender, frame_receiver) = bounded::<ProbedFrame>(frames.max(1));
+    let callbacks = AppSinkCallbacks::builder()
+        .new_sample(move |sink| {
+            let sample = sink.pull_sample().map_err(|_| gst::FlowError::Error)?;
+            let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+            let caps = sample.caps().ok_or(gst::FlowError::NotNegotiated)?;
+            let video_info =
+                gst_video::VideoInfo::from_caps(caps).map_err(|_| gst::FlowError::NotNegotiated)?;
+
+            match frame_sender.try_send(ProbedFrame {
+                pts: buffer.pts(),
+                video_info,
+            }) {
+                Ok(()) => Ok(gst::FlowSuccess::Ok),
+                Err(_) => Err(gst::FlowError::Eos),
+            }
+        })
+        .build();
+
+    let pipeline = pipeline::build(
+        source.as_ref(),
+        true,
+        pipeline::Callbacks {
+            frames: callbacks,
+            encoded: None,
+        },
+    )?;
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("failed to start pipeline")?;
+
+    let result = collect_frames(&pipeline, &frame_receiver, timeout, frames);
+    let decoder = describe_decoder(&pipeline);
+
+    pipeline
+        .set_state(gst::State::Null)
+        .context("failed to stop pipeline")?;
+
+    let probed = result?;
+    let first = &probed[0];
+
+    match decoder {
+        Some((name, sink_caps, src_caps)) => {
+            println!("Decoder:     {name}");
+            println!("Codec caps:  {sink_caps}");
+            println!("Raw caps:    {src_caps}");
+        }
+        None => println!("Decoder:     none (source produces raw video)"),
+    }
+    println!(
+        "Resolution:  {}x{}",
+        first.video_info.width(),
+        first.video_info.height()
+    );
+
+    let fps = first.video_info.fps();
+    if fps.numer() > 0 {
+        println!(
+            "Frame rate:  {}/{} ({:.2} fps declared)",
+            fps.numer(),
+            fps.denom(),
+            fps.numer() as f64 / fps.denom() as f64
+        );
+    } else {
+        println!("Frame rate:  variable");
+    }
+
+    match (
+        probed.first().and_then(|frame| frame.pts),
+        probed.last().and_then(|frame| frame.pts),
+    ) {
+        (Some(first_pts), Some(last_pts)) if probed.len() > 1 && last_pts > first_pts => {
+            let elapsed = (last_pts - first_pts).nseconds() as f64 / 1e9;
+            println!(
+                "Measured:    {:.2} fps over {} frames",
+                (probed.len() - 1) as f64 / elapsed,
+                probed.len()
+            );
+        }
+        _ => println!("Measured:    not enough timestamped frames"),
+    }
+
+    Ok(())
+}
+
+/// Receive up to `frames` frames, stopping early on EOS or when `timeout`
+/// passes without any frame.
+fn collect_frames(
+    pipeline: &gst::Pipeline,
+    frame_receiver: &crossbeam::channel::Receiver<ProbedFrame>,
+    timeout: Duration,
+    frames: usize,
+) -> anyhow::Result<Vec<ProbedFrame>> {
+    let bus = pipeline.bus().context("failed to get bus")?;
+    let mut probed = Vec::with_capacity(frames);
+    let mut last_frame_at = Instant::now();
+
+    while probed.len() < frames {
+        while let Some(msg) = bus.pop() {
+            match msg.view() {
+                gst::MessageView::Eos(..) if !probed.is_empty() => return Ok(probed),
+                gst::MessageView::Eos(..) => bail!("end of stream before the first frame"),
+                gst::MessageView::Error(err) => bail!(
+                    "Error from {}: {}",
+                    err.src().map(|s| s.path_string()).unwrap_or("<?>".into()),
+                    err.error()
+                ),
+                _ => (),
+            }
+        }
+
+        match frame_receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(frame) => {
+                probed.push(frame);
+                last_frame_at = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if last_frame_at.elapsed() < timeout => {}
+            Err(_) if !probed.is_empty() => return Ok(probed),
+            Err(_) => bail!("no frame received within {:?}", timeout),
+        }
+    }
+
+    Ok(probed)
+}
+
+/// Find the video decoder the source ended up with, returning its factory
+/// name and the caps on both sides.
+fn describe_decoder(pipeline: &gst::Pipeline) -> Option<(String, String, String)> {
+    let decoder = pipeline
+        .iterate_recurse()
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|element| {
+            element.factory().is_some_and(|factory| {
+                let klass = factory.metadata("klass").unwrap_or_default();
+                klass.contains("Decoder") && klass.contains("Video")
+            })
+        })?;
+
+    let pad_caps = |pad_name: &str| {
+        decoder
+            .static_pad(pad_name)
+            .and_then(|pad| pad.current_caps())
+            .map(|caps| caps.to_string())
+            .unwrap_or_else(|| "<not negotiated>".to_string())
+    };
+
+    Some((
+        decoder
+            .factory()
+            .map(|factory| factory.name().to_string())
+            .unwrap_or_default(),
+        pad_caps("sink"),
+        pad_caps("src"),
+    ))
+}
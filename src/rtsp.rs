@@ -0,0 +1,230 @@
+use crate::annotate;
+use crate::config::Config;
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use glib::object::Cast;
+use gst::prelude::*;
+use gst_rtsp_server::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gstreamer_rtsp_server as gst_rtsp_server;
+use gstreamer_video as gst_video;
+use image::DynamicImage;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtspConfig {
+    /// Address the server listens on.
+    pub address: String,
+    pub port: u16,
+    /// Mount path of each camera's annotated stream, with a `{camera}`
+    /// placeholder. Cameras may override it with their `rtsp_mount_path`.
+    pub mount_path: String,
+    /// Target bitrate of the H.264 encoder, in kbit/s.
+    pub bitrate: u32,
+}
+
+impl Default for RtspConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: 8554,
+            mount_path: "/{camera}".to_string(),
+            bitrate: 2000,
+        }
+    }
+}
+
+impl RtspConfig {
+    pub const PLACEHOLDERS: &[&str] = &["camera"];
+}
+
+/// The app sources of the streams being watched, by camera.
+type Sources = Arc<Mutex<HashMap<String, gst_app::AppSrc>>>;
+
+/// Re-publishes the inferred frames with their detections drawn onto them
+/// as H.264 over RTSP, one mount point per camera.
+///
+/// Streams only carry the inferred frames, so their frame rate is the
+/// sampling rate, with a keyframe about every second. Nothing is encoded
+/// while nobody watches. The configured cameras are mounted upfront, the
+/// others, such as those added through the API, with their first inferred
+/// frame; mount points stay until exit.
+pub struct RtspSink {
+    config: Arc<Config>,
+    mounts: gst_rtsp_server::RTSPMountPoints,
+    mounted: HashSet<String>,
+    sources: Sources,
+    main_loop: glib::MainLoop,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RtspSink {
+    pub fn start(config: Arc<Config>) -> anyhow::Result<Self> {
+        let rtsp = &config.rtsp;
+        let server = gst_rtsp_server::RTSPServer::new();
+        server.set_address(&rtsp.address);
+        server.set_service(&rtsp.port.to_string());
+
+        let mounts = server
+            .mount_points()
+            .context("RTSP server has no mount points")?;
+
+        // The server runs on its own context, so that it does not depend on
+        // anyone else iterating the default one
+        let context = glib::MainContext::new();
+        server
+            .attach(Some(&context))
+            .with_context(|| format!("failed to listen on {}:{}", rtsp.address, rtsp.port))?;
+        let main_loop = glib::MainLoop::new(Some(&context), false);
+
+        let thread = std::thread::Builder::new()
+            .name("rtsp".to_string())
+            .spawn({
+                let main_loop = main_loop.clone();
+                // Keep the server alive as long as the loop runs
+                move || {
+                    let _server = server;
+                    main_loop.run();
+                }
+            })
+            .context("failed to spawn RTSP server thread")?;
+
+        let mut sink = Self {
+            config: config.clone(),
+            mounts,
+            mounted: HashSet::new(),
+            sources: Sources::default(),
+            main_loop,
+            thread: Some(thread),
+        };
+        for camera in &config.cameras {
+            sink.mount(&camera.id);
+        }
+
+        Ok(sink)
+    }
+
+    /// Serve the annotated stream of a camera, unless it already is.
+    fn mount(&mut self, camera_id: &str) {
+        if self.mounted.contains(camera_id) {
+            return;
+        }
+
+        let rtsp = &self.config.rtsp;
+        let path = self.config.rtsp_mount_path(camera_id);
+        self.mounts.add_factory(
+            &path,
+            media_factory(camera_id, &self.config, self.sources.clone()),
+        );
+        self.mounted.insert(camera_id.to_string());
+        tracing::info!(
+            "Serving annotated {} on rtsp://{}:{}{}",
+            camera_id,
+            rtsp.address,
+            rtsp.port,
+            path
+        );
+    }
+}
+
+/// A shared media per camera, fed by the app source registered in `sources`
+/// while it is prepared.
+fn media_factory(
+    camera_id: &str,
+    config: &Config,
+    sources: Sources,
+) -> gst_rtsp_server::RTSPMediaFactory {
+    // Clients joining a shared media wait for its next keyframe, so send one
+    // about every second of the sampling rate
+    let key_int_max = (config.sampling.rate.round() as u32).max(1);
+
+    let factory = gst_rtsp_server::RTSPMediaFactory::new();
+    factory.set_launch(&format!(
+        "( appsrc name=src is-live=true format=time do-timestamp=true \
+         ! videoconvert ! video/x-raw,format=I420 \
+         ! x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max={} \
+         ! rtph264pay name=pay0 pt=96 config-interval=1 )",
+        config.rtsp.bitrate, key_int_max
+    ));
+    factory.set_shared(true);
+
+    let camera_id = camera_id.to_string();
+    factory.connect_media_configure(move |_, media| {
+        let Some(source) = media
+            .element()
+            .downcast_ref::<gst::Bin>()
+            .and_then(|bin| bin.by_name_recurse_up("src"))
+            .and_then(|element| element.downcast::<gst_app::AppSrc>().ok())
+        else {
+            tracing::warn!("Annotated stream of {} has no app source", camera_id);
+            return;
+        };
+
+        tracing::info!("A client started watching {}", camera_id);
+        sources.lock().unwrap().insert(camera_id.clone(), source);
+
+        let sources = sources.clone();
+        let camera_id = camera_id.clone();
+        media.connect_unprepared(move |_| {
+            tracing::info!("Nobody is watching {} anymore", camera_id);
+            sources.lock().unwrap().remove(&camera_id);
+        });
+    });
+
+    factory
+}
+
+impl DetectionSink for RtspSink {
+    fn name(&self) -> &'static str {
+        "rtsp"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        let camera_id = &*inference.frame.camera_id;
+        self.mount(camera_id);
+        let Some(source) = self.sources.lock().unwrap().get(camera_id).cloned() else {
+            return Ok(());
+        };
+
+        // Four bytes per pixel keep the rows 4-byte aligned, as GStreamer
+        // assumes of raw video without a video meta
+        let image = annotate::annotate(&inference.frame.image, &inference.detections);
+        let image = DynamicImage::ImageRgb8(image).into_rgba8();
+        let caps = gst_video::VideoCapsBuilder::new()
+            .format(gst_video::VideoFormat::Rgbx)
+            .width(image.width() as i32)
+            .height(image.height() as i32)
+            .framerate(gst::Fraction::new(0, 1))
+            .build();
+        // The resolution may change, e.g. after a reconnection
+        if source.caps().as_ref() != Some(&caps) {
+            source.set_caps(Some(&caps));
+        }
+
+        if let Err(err) = source.push_buffer(gst::Buffer::from_mut_slice(image.into_raw())) {
+            // The client went away in the meantime
+            tracing::debug!("Dropping annotated frame of {}: {:?}", camera_id, err);
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        for source in self.sources.lock().unwrap().values() {
+            let _ = source.end_of_stream();
+        }
+        self.main_loop.quit();
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("RTSP server thread panicked"))?;
+        }
+
+        Ok(())
+    }
+}
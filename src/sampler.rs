@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// Picks the frames to run inference on so that, whatever the stream's frame
+/// rate, about `rate` frames per second of stream time are sampled.
+pub struct FrameSampler {
+    interval: Duration,
+    next_due: Option<Duration>,
+    started_at: Instant,
+}
+
+impl FrameSampler {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next_due: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Decide whether the frame at `timestamp` should be sampled.
+    ///
+    /// Frames without a timestamp are timed with the wall clock instead.
+    pub fn should_sample(&mut self, timestamp: Option<Duration>) -> bool {
+        let timestamp = timestamp.unwrap_or_else(|| self.started_at.elapsed());
+
+        match self.next_due {
+            // Not due yet, and time did not go backwards (e.g. after a seek).
+            Some(due) if timestamp < due && timestamp + self.interval >= due => false,
+            // Keep a steady cadence unless we fell more than one interval behind.
+            Some(due) if timestamp >= due && timestamp - due < self.interval => {
+                self.next_due = Some(due + self.interval);
+                true
+            }
+            _ => {
+                self.next_due = Some(timestamp + self.interval);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn samples_at_the_rate_of_the_stream_time() {
+        let mut sampler = FrameSampler::new(10.0);
+        let sampled = (0..30)
+            .filter(|&k| sampler.should_sample(Some(Duration::from_secs(k) / 30)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(sampled, [0, 3, 6, 9, 12, 15, 18, 21, 24, 27]);
+    }
+
+    #[test]
+    fn starts_over_after_a_jump_forward() {
+        let mut sampler = FrameSampler::new(10.0);
+
+        assert!(sampler.should_sample(ms(0)));
+        assert!(sampler.should_sample(ms(100)));
+        assert!(sampler.should_sample(ms(5_000)));
+        assert!(!sampler.should_sample(ms(5_050)));
+        assert!(sampler.should_sample(ms(5_100)));
+    }
+
+    #[test]
+    fn starts_over_after_a_step_backwards() {
+        let mut sampler = FrameSampler::new(10.0);
+
+        assert!(sampler.should_sample(ms(5_000)));
+        assert!(sampler.should_sample(ms(1_000)));
+        assert!(!sampler.should_sample(ms(1_050)));
+        assert!(sampler.should_sample(ms(1_100)));
+    }
+
+    #[test]
+    fn times_frames_without_timestamp_with_the_wall_clock() {
+        let mut sampler = FrameSampler::new(20.0);
+
+        assert!(sampler.should_sample(None));
+        assert!(!sampler.should_sample(None));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(sampler.should_sample(None));
+    }
+}
@@ -0,0 +1,422 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// What [`FairQueue::push`] does when the lane is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackpressurePolicy {
+    /// Wait for room, stalling the producer.
+    Block,
+    /// Discard the item being pushed.
+    DropNewest,
+    /// Discard the oldest queued item so that the latest one wins.
+    DropOldest,
+}
+
+/// The result of a successful [`FairQueue::push`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// The lane was full and an item was discarded; carries the number of
+    /// items discarded from this lane so far.
+    Dropped {
+        total_dropped: u64,
+    },
+}
+
+/// A snapshot of one lane.
+#[derive(Debug, Clone)]
+pub struct LaneStats {
+    pub key: String,
+    pub depth: usize,
+    pub dropped: u64,
+}
+
+/// A multi-producer, multi-consumer queue with one bounded lane per key.
+///
+/// Consumers take items from the lanes in round-robin order, so a key that
+/// produces a lot (e.g. a busy camera) can fill only its own lane and cannot
+/// starve the others. A lane is claimed by one consumer at a time, until it
+/// drops the [`Claim`] of its item, so that the items of a key are handled
+/// one after the other and in order.
+pub struct FairQueue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct State<T> {
+    lanes: Vec<Lane<T>>,
+    lane_capacity: usize,
+    cursor: usize,
+    closed: bool,
+}
+
+struct Lane<T> {
+    key: String,
+    items: VecDeque<T>,
+    dropped: u64,
+    /// Whether a consumer holds a [`Claim`] on the lane.
+    claimed: bool,
+}
+
+/// Keeps the lane of an item from [`FairQueue::pop`] to its consumer until
+/// dropped, even by a panic.
+pub struct Claim<'a, T> {
+    queue: &'a FairQueue<T>,
+    key: String,
+}
+
+impl<T> Drop for Claim<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self
+            .queue
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(lane) = state.lanes.iter_mut().find(|lane| lane.key == self.key) {
+            lane.claimed = false;
+        }
+        self.queue.not_empty.notify_all();
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn new(lane_capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                lanes: Vec::new(),
+                lane_capacity: lane_capacity.max(1),
+                cursor: 0,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Append `item` to the lane of `key`, applying `policy` if that lane
+    /// is full.
+    ///
+    /// Returns the item back if the queue has been closed.
+    pub fn push(&self, key: &str, item: T, policy: BackpressurePolicy) -> Result<Pushed, T> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.closed {
+                return Err(item);
+            }
+
+            let capacity = state.lane_capacity;
+            let lane = state.lane_mut(key);
+            if lane.items.len() < capacity {
+                lane.items.push_back(item);
+                self.not_empty.notify_one();
+                return Ok(Pushed::Queued);
+            }
+
+            match policy {
+                BackpressurePolicy::Block => {
+                    state = self.not_full.wait(state).unwrap();
+                }
+                BackpressurePolicy::DropNewest => {
+                    lane.dropped += 1;
+                    return Ok(Pushed::Dropped {
+                        total_dropped: lane.dropped,
+                    });
+                }
+                BackpressurePolicy::DropOldest => {
+                    lane.items.pop_front();
+                    lane.items.push_back(item);
+                    lane.dropped += 1;
+                    self.not_empty.notify_one();
+                    return Ok(Pushed::Dropped {
+                        total_dropped: lane.dropped,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Take the next item of the lanes nobody has claimed, visiting them in
+    /// round-robin order, and claim its lane.
+    ///
+    /// Blocks until an item is available; returns `None` once the queue is
+    /// closed and drained.
+    pub fn pop(&self) -> Option<(T, Claim<'_, T>)> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some((key, item)) = state.pop_next() {
+                self.not_full.notify_all();
+                return Some((item, Claim { queue: self, key }));
+            }
+            if state.closed && state.lanes.iter().all(|lane| lane.items.is_empty()) {
+                return None;
+            }
+
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Stop accepting items and wake up everyone waiting on the queue.
+    ///
+    /// Items already queued are still handed out by [`FairQueue::pop`].
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// Whether the queue is closed and nothing is left in it.
+    pub fn is_drained(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.closed && state.lanes.iter().all(|lane| lane.items.is_empty())
+    }
+
+    /// Discard every queued item, counting it as dropped, and return how
+    /// many there were.
+    pub fn discard(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut discarded = 0;
+        for lane in &mut state.lanes {
+            discarded += lane.items.len();
+            lane.dropped += lane.items.len() as u64;
+            lane.items.clear();
+        }
+        self.not_full.notify_all();
+
+        discarded
+    }
+
+    /// Wait for the lane of `key` to be released, then remove it along with
+    /// its items, and return how many there were.
+    pub fn remove(&self, key: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        while state
+            .lanes
+            .iter()
+            .any(|lane| lane.key == key && lane.claimed)
+        {
+            state = self.not_empty.wait(state).unwrap();
+        }
+
+        let Some(index) = state.lanes.iter().position(|lane| lane.key == key) else {
+            return 0;
+        };
+        let lane = state.lanes.remove(index);
+        if state.cursor > index {
+            state.cursor -= 1;
+        }
+        if state.cursor >= state.lanes.len() {
+            state.cursor = 0;
+        }
+        self.not_full.notify_all();
+
+        lane.items.len()
+    }
+
+    /// Depth and drop counters of every lane.
+    pub fn stats(&self) -> Vec<LaneStats> {
+        self.state
+            .lock()
+            .unwrap()
+            .lanes
+            .iter()
+            .map(|lane| LaneStats {
+                key: lane.key.clone(),
+                depth: lane.items.len(),
+                dropped: lane.dropped,
+            })
+            .collect()
+    }
+}
+
+impl<T> State<T> {
+    fn lane_mut(&mut self, key: &str) -> &mut Lane<T> {
+        let index = match self.lanes.iter().position(|lane| lane.key == key) {
+            Some(index) => index,
+            None => {
+                self.lanes.push(Lane {
+                    key: key.to_string(),
+                    items: VecDeque::new(),
+                    dropped: 0,
+                    claimed: false,
+                });
+                self.lanes.len() - 1
+            }
+        };
+
+        &mut self.lanes[index]
+    }
+
+    fn pop_next(&mut self) -> Option<(String, T)> {
+        let lane_count = self.lanes.len();
+
+        for offset in 0..lane_count {
+            let index = (self.cursor + offset) % lane_count;
+            let lane = &mut self.lanes[index];
+            if lane.claimed {
+                continue;
+            }
+            if let Some(item) = lane.items.pop_front() {
+                lane.claimed = true;
+                self.cursor = (index + 1) % lane_count;
+                return Some((lane.key.clone(), item));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Long enough for a blocked thread to have gone on had it not blocked.
+    const SETTLE: Duration = Duration::from_millis(50);
+
+    /// Pop an item and release its lane right away.
+    fn pop(queue: &FairQueue<u32>) -> Option<u32> {
+        queue.pop().map(|(item, _claim)| item)
+    }
+
+    #[test]
+    fn drop_newest_discards_the_pushed_item() {
+        let queue = FairQueue::new(2);
+
+        assert_eq!(
+            queue.push("a", 1, BackpressurePolicy::DropNewest),
+            Ok(Pushed::Queued)
+        );
+        assert_eq!(
+            queue.push("a", 2, BackpressurePolicy::DropNewest),
+            Ok(Pushed::Queued)
+        );
+        assert_eq!(
+            queue.push("a", 3, BackpressurePolicy::DropNewest),
+            Ok(Pushed::Dropped { total_dropped: 1 })
+        );
+        assert_eq!(pop(&queue), Some(1));
+        assert_eq!(pop(&queue), Some(2));
+        assert_eq!(queue.stats()[0].dropped, 1);
+    }
+
+    #[test]
+    fn drop_oldest_discards_the_first_queued_item() {
+        let queue = FairQueue::new(2);
+
+        queue.push("a", 1, BackpressurePolicy::DropOldest).unwrap();
+        queue.push("a", 2, BackpressurePolicy::DropOldest).unwrap();
+        assert_eq!(
+            queue.push("a", 3, BackpressurePolicy::DropOldest),
+            Ok(Pushed::Dropped { total_dropped: 1 })
+        );
+        assert_eq!(pop(&queue), Some(2));
+        assert_eq!(pop(&queue), Some(3));
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let queue = FairQueue::new(1);
+        queue.push("a", 1, BackpressurePolicy::Block).unwrap();
+
+        thread::scope(|scope| {
+            let pusher = scope.spawn(|| queue.push("a", 2, BackpressurePolicy::Block));
+            thread::sleep(SETTLE);
+            assert!(!pusher.is_finished());
+
+            assert_eq!(pop(&queue), Some(1));
+            assert_eq!(pusher.join().unwrap(), Ok(Pushed::Queued));
+        });
+        assert_eq!(pop(&queue), Some(2));
+    }
+
+    #[test]
+    fn lanes_take_turns() {
+        let queue = FairQueue::new(10);
+        for item in [1, 2, 3] {
+            queue.push("a", item, BackpressurePolicy::Block).unwrap();
+        }
+        queue.push("b", 10, BackpressurePolicy::Block).unwrap();
+
+        let popped = (0..4).filter_map(|_| pop(&queue)).collect::<Vec<_>>();
+        assert_eq!(popped, [1, 10, 2, 3]);
+    }
+
+    #[test]
+    fn claimed_lanes_are_skipped() {
+        let queue = FairQueue::new(10);
+        queue.push("a", 1, BackpressurePolicy::Block).unwrap();
+        queue.push("a", 2, BackpressurePolicy::Block).unwrap();
+        queue.push("b", 10, BackpressurePolicy::Block).unwrap();
+
+        let (first, claim) = queue.pop().unwrap();
+        assert_eq!(first, 1);
+        assert_eq!(pop(&queue), Some(10));
+
+        thread::scope(|scope| {
+            let consumer = scope.spawn(|| pop(&queue));
+            thread::sleep(SETTLE);
+            assert!(!consumer.is_finished());
+
+            drop(claim);
+            assert_eq!(consumer.join().unwrap(), Some(2));
+        });
+    }
+
+    #[test]
+    fn remove_waits_for_the_claim() {
+        let queue = FairQueue::new(10);
+        queue.push("a", 1, BackpressurePolicy::Block).unwrap();
+        queue.push("a", 2, BackpressurePolicy::Block).unwrap();
+        let (_, claim) = queue.pop().unwrap();
+
+        thread::scope(|scope| {
+            let remover = scope.spawn(|| queue.remove("a"));
+            thread::sleep(SETTLE);
+            assert!(!remover.is_finished());
+
+            drop(claim);
+            assert_eq!(remover.join().unwrap(), 1);
+        });
+        assert!(queue.stats().is_empty());
+    }
+
+    #[test]
+    fn close_wakes_blocked_pushers() {
+        let queue = FairQueue::new(1);
+        queue.push("a", 1, BackpressurePolicy::Block).unwrap();
+
+        thread::scope(|scope| {
+            let pusher = scope.spawn(|| queue.push("a", 2, BackpressurePolicy::Block));
+            thread::sleep(SETTLE);
+
+            queue.close();
+            assert_eq!(pusher.join().unwrap(), Err(2));
+        });
+        assert_eq!(pop(&queue), Some(1));
+        assert_eq!(pop(&queue), None);
+        assert!(queue.is_drained());
+    }
+
+    #[test]
+    fn discard_wakes_blocked_pushers() {
+        let queue = FairQueue::new(1);
+        queue.push("a", 1, BackpressurePolicy::Block).unwrap();
+
+        thread::scope(|scope| {
+            let pusher = scope.spawn(|| queue.push("a", 2, BackpressurePolicy::Block));
+            thread::sleep(SETTLE);
+
+            assert_eq!(queue.discard(), 1);
+            assert_eq!(pusher.join().unwrap(), Ok(Pushed::Queued));
+        });
+        assert_eq!(pop(&queue), Some(2));
+        assert_eq!(queue.stats()[0].dropped, 1);
+    }
+}
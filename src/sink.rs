@@ -0,0 +1,257 @@
+use crate::config::{Config, CropMode, SinkKind};
+use crate::crops::{self, CropSink};
+use crate::event::{DetectionEvent, Event, JsonLinesSink};
+use crate::inference::{Detection, Frame};
+use crate::metrics::metrics;
+use crate::mqtt::MqttSink;
+use crate::rtsp::RtspSink;
+use crate::store::DatabaseSink;
+use crate::webhook::WebhookSink;
+use crossbeam::channel::{self, RecvTimeoutError, SendError, Sender, TrySendError};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often [`DetectionSink::idle`] is called while no inference arrives.
+const IDLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The outcome of running inference on one frame, as handed to every sink.
+pub struct Inference {
+    pub frame: Frame,
+    pub detections: Vec<Detection>,
+    pub event: DetectionEvent,
+}
+
+impl Inference {
+    /// Describe the inference of `frame`, saving the crops of its detections
+    /// first if every one is saved, so that events only refer to crops that
+    /// exist.
+    pub fn new(
+        config: &Config,
+        frame: Frame,
+        detections: Vec<Detection>,
+        clip_path: Option<PathBuf>,
+    ) -> Self {
+        let crop_paths = if config.output.sinks.contains(&SinkKind::Crops)
+            && config.output.crop_mode == CropMode::EveryFrame
+        {
+            crops::save_crops(config, &frame, &detections)
+        } else {
+            Vec::new()
+        };
+
+        let mut event = DetectionEvent::new(
+            &frame.camera_id,
+            frame.id,
+            frame.pts,
+            &config.model.path,
+            frame.image.width(),
+            frame.image.height(),
+            &detections,
+            &crop_paths,
+        );
+        event.clip_path = clip_path;
+
+        Self {
+            frame,
+            detections,
+            event,
+        }
+    }
+}
+
+/// Somewhere detections are delivered to.
+///
+/// Every sink runs on its own thread and is fed through its own bounded
+/// queue, so a slow sink only loses its own backlog.
+pub trait DetectionSink: Send {
+    fn name(&self) -> &'static str;
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()>;
+
+    /// Called for everything that happens to the tracked objects, such as
+    /// a track starting or an object entering a zone.
+    fn handle_event(&mut self, _event: &Event) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called periodically while no inference arrives, e.g. to flush a
+    /// batch that has waited long enough.
+    fn idle(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the last inference has been handled.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Build the sinks enabled in the configuration.
+pub fn from_config(config: &Arc<Config>) -> anyhow::Result<Vec<Box<dyn DetectionSink>>> {
+    config
+        .output
+        .sinks
+        .iter()
+        .map(|kind| -> anyhow::Result<Box<dyn DetectionSink>> {
+            Ok(match kind {
+                SinkKind::Crops => Box::new(CropSink::new(config.clone())),
+                SinkKind::JsonLines => Box::new(JsonLinesSink::open(&config.output.events)?),
+                SinkKind::Mqtt => Box::new(MqttSink::connect(config.clone())?),
+                SinkKind::Webhook => Box::new(WebhookSink::new(config.clone())?),
+                SinkKind::Database => Box::new(DatabaseSink::open(config)?),
+                SinkKind::Rtsp => Box::new(RtspSink::start(config.clone())?),
+            })
+        })
+        .collect()
+}
+
+/// Fans inferences out to the sinks, each on its own thread.
+pub struct Dispatcher {
+    outlets: Vec<Outlet>,
+}
+
+/// What goes through the queue of a sink.
+#[derive(Clone)]
+enum Message {
+    Inference(Arc<Inference>),
+    Event(Arc<Event>),
+}
+
+struct Outlet {
+    name: &'static str,
+    sender: Sender<Message>,
+    dropped: AtomicU64,
+    thread: JoinHandle<()>,
+}
+
+impl Dispatcher {
+    /// Start a thread per sink, each with a queue of `depth` inferences.
+    pub fn start(sinks: Vec<Box<dyn DetectionSink>>, depth: usize) -> Self {
+        let outlets = sinks
+            .into_iter()
+            .map(|mut sink| {
+                let name = sink.name();
+                let (sender, receiver) = channel::bounded::<Message>(depth.max(1));
+
+                let thread = std::thread::Builder::new()
+                    .name(format!("sink-{name}"))
+                    .spawn(move || {
+                        loop {
+                            match receiver.recv_timeout(IDLE_INTERVAL) {
+                                Ok(Message::Inference(inference)) => {
+                                    if let Err(err) = sink.handle(&inference) {
+                                        metrics().sink_failed(name);
+                                        tracing::warn!(
+                                            "Sink {} failed on frame {} of {}: {:#}",
+                                            name,
+                                            inference.frame.id,
+                                            inference.frame.camera_id,
+                                            err
+                                        );
+                                    }
+                                }
+                                Ok(Message::Event(event)) => {
+                                    if let Err(err) = sink.handle_event(&event) {
+                                        metrics().sink_failed(name);
+                                        tracing::warn!(
+                                            "Sink {} failed on a {} event of {}: {:#}",
+                                            name,
+                                            event.type_name(),
+                                            event.camera_id(),
+                                            err
+                                        );
+                                    }
+                                }
+                                Err(RecvTimeoutError::Timeout) => {
+                                    if let Err(err) = sink.idle() {
+                                        metrics().sink_failed(name);
+                                        tracing::warn!("Sink {} failed: {:#}", name, err);
+                                    }
+                                }
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            }
+                        }
+                        if let Err(err) = sink.finish() {
+                            tracing::warn!("Sink {} failed to finish: {:#}", name, err);
+                        }
+                    })
+                    .expect("failed to spawn sink thread");
+
+                Outlet {
+                    name,
+                    sender,
+                    dropped: AtomicU64::new(0),
+                    thread,
+                }
+            })
+            .collect();
+
+        Self { outlets }
+    }
+
+    /// Queue `inference` on every sink. Sinks whose queue is full skip the
+    /// frames of live sources, and hold up the others until they catch up.
+    pub fn dispatch(&self, inference: Inference) {
+        let wait = !inference.frame.live;
+        self.send(Message::Inference(Arc::new(inference)), wait);
+    }
+
+    /// Queue an event on every sink, waiting for room: events are only
+    /// emitted once, so skipping one would leave a track or zone open.
+    pub fn dispatch_event(&self, event: Event) {
+        self.send(Message::Event(Arc::new(event)), true);
+    }
+
+    fn send(&self, message: Message, wait: bool) {
+        for outlet in &self.outlets {
+            let result = if wait {
+                outlet
+                    .sender
+                    .send(message.clone())
+                    .map_err(|SendError(message)| TrySendError::Disconnected(message))
+            } else {
+                outlet.sender.try_send(message.clone())
+            };
+            match result {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => {
+                    metrics().sink_dropped(outlet.name);
+                    let dropped = outlet.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if dropped == 1 || dropped % 100 == 0 {
+                        tracing::warn!(
+                            "Sink {} is falling behind: {} events dropped",
+                            outlet.name,
+                            dropped
+                        );
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    tracing::warn!("Sink {} has stopped", outlet.name);
+                }
+            }
+        }
+    }
+
+    /// Let every sink drain its queue, then wait for it to finish.
+    pub fn close(self) {
+        let threads = self
+            .outlets
+            .into_iter()
+            .map(|outlet| (outlet.name, outlet.dropped, outlet.thread))
+            .collect::<Vec<_>>();
+
+        for (name, dropped, thread) in threads {
+            if thread.join().is_err() {
+                tracing::error!("Sink {} panicked", name);
+            }
+
+            let dropped = dropped.into_inner();
+            if dropped > 0 {
+                tracing::info!("Sink {}: {} events dropped", name, dropped);
+            }
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::codec::{self, RtpVideoCodec};
+use anyhow::{Context, bail};
+use glib::object::Cast;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+use std::path::{Path, PathBuf};
+
+/// A producer of raw video frames.
+///
+/// Every source adds its own elements into the pipeline and links its decoded
+/// video output to `downstream`, which is the first element of the shared
+/// `videoconvert → identity → appsink` tail. Sources receiving H.264 also
+/// link it, before decoding, to `encoded` when it is given.
+pub trait FrameSource: Send + Sync {
+    /// A short human-readable description, used in logs.
+    fn describe(&self) -> String;
+
+    /// Whether the source produces frames in real time, if it can tell before
+    /// its pipeline is built. Otherwise, the supervisor finds out when it
+    /// pauses the pipeline; see [`Liveness`](crate::supervisor::Liveness).
+    ///
+    /// Live sources are rendered with `sync=true` so that the appsink follows
+    /// the clock; offline sources are consumed as fast as inference allows.
+    fn is_live(&self) -> Option<bool>;
+
+    /// Add the source elements into `pipeline` and link them to `downstream`
+    /// and, if they can, to `encoded`.
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()>;
+}
+
+/// Parse a command-line source argument.
+///
+/// - `rtsp://…` / `rtsps://…`: an RTSP camera
+/// - `test:` or `test:<pattern>`: a `videotestsrc` with an optional pattern
+/// - `pipeline:<description>`: a `gst-launch`-style pipeline description
+/// - `file://…` or a path to a file: a local video file
+/// - a path to a directory: a directory of still images
+pub fn from_argument(argument: &str) -> anyhow::Result<Box<dyn FrameSource>> {
+    if argument.starts_with("rtsp://") || argument.starts_with("rtsps://") {
+        return Ok(Box::new(RtspSource {
+            location: argument.to_string(),
+        }));
+    }
+
+    if let Some(pattern) = argument.strip_prefix("test:") {
+        return Ok(Box::new(TestSource {
+            pattern: (!pattern.is_empty()).then(|| pattern.to_string()),
+        }));
+    }
+
+    if let Some(description) = argument.strip_prefix("pipeline:") {
+        return Ok(Box::new(PipelineDescriptionSource {
+            description: description.to_string(),
+        }));
+    }
+
+    let path = match argument.strip_prefix("file://") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(argument),
+    };
+
+    if path.is_dir() {
+        return Ok(Box::new(ImageDirectorySource::new(&path)?));
+    }
+    if path.is_file() {
+        return Ok(Box::new(VideoFileSource { path }));
+    }
+
+    bail!("unrecognized source: {argument}")
+}
+
+/// An RTSP camera.
+///
+/// The depayloader and decoder are chosen from the `encoding-name` of the RTP
+/// caps when `rtspsrc` exposes its pads; see [`RtpVideoCodec`].
+pub struct RtspSource {
+    pub location: String,
+}
+
+impl FrameSource for RtspSource {
+    fn describe(&self) -> String {
+        format!("RTSP stream {}", self.location)
+    }
+
+    fn is_live(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()> {
+        let rtspsrc_element = gst::ElementFactory::make("rtspsrc")
+            .property("location", &self.location)
+            .build()
+            .context("failed to create rtspsrc element")?;
+
+        pipeline.add(&rtspsrc_element)?;
+
+        let pipeline_weak = pipeline.downgrade();
+        let downstream = downstream.clone();
+        let encoded = encoded.cloned();
+        rtspsrc_element.connect_pad_added(move |rtspsrc, src_pad| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+
+            if let Err(err) = link_rtp_video_pad(&pipeline, src_pad, &downstream, encoded.as_ref())
+            {
+                gst::element_error!(rtspsrc, gst::StreamError::CodecNotFound, ("{:#}", err));
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Build `rtpjitterbuffer → depayloader → decoder` for a newly exposed
+/// `rtspsrc` pad and link it between `src_pad` and `downstream`.
+///
+/// With `encoded` and an H.264 stream, the depayloader output is teed to it:
+/// `depayloader → tee → queue → decoder` and `tee → encoded`.
+fn link_rtp_video_pad(
+    pipeline: &gst::Pipeline,
+    src_pad: &gst::Pad,
+    downstream: &gst::Element,
+    encoded: Option<&gst::Element>,
+) -> anyhow::Result<()> {
+    let caps = src_pad
+        .current_caps()
+        .unwrap_or_else(|| src_pad.query_caps(None));
+    let structure = caps.structure(0).context("RTP pad has no caps")?;
+
+    let media = structure.get::<&str>("media").unwrap_or_default();
+    if media != "video" {
+        tracing::info!("Ignoring {} stream on pad {}", media, src_pad.name());
+        return Ok(());
+    }
+
+    let sink_pad = downstream.static_pad("sink").unwrap();
+    if sink_pad.is_linked() {
+        tracing::info!("Ignoring additional video stream on pad {}", src_pad.name());
+        return Ok(());
+    }
+
+    let encoding_name = structure
+        .get::<&str>("encoding-name")
+        .context("RTP caps have no encoding-name")?;
+    let codec = RtpVideoCodec::from_encoding_name(encoding_name).with_context(|| {
+        format!(
+            "unsupported RTP video encoding `{}` (supported: {})",
+            encoding_name,
+            codec::supported_encoding_names()
+        )
+    })?;
+    tracing::info!("Negotiated {} video stream", codec);
+
+    let rtpjitterbuffer_element = gst::ElementFactory::make("rtpjitterbuffer")
+        .build()
+        .context("failed to create rtpjitterbuffer element")?;
+    let depayloader_element = codec.make_depayloader()?;
+    let decoder_element = codec.make_decoder()?;
+
+    let mut chain = vec![rtpjitterbuffer_element.clone(), depayloader_element];
+    let tee_element = match encoded {
+        Some(_) if codec == RtpVideoCodec::H264 => {
+            let tee_element = gst::ElementFactory::make("tee")
+                .build()
+                .context("failed to create tee element")?;
+            let queue_element = gst::ElementFactory::make("queue")
+                .build()
+                .context("failed to create queue element")?;
+            chain.extend([tee_element.clone(), queue_element]);
+            Some(tee_element)
+        }
+        Some(_) => {
+            tracing::warn!(
+                "Not recording the {} stream: only H.264 is supported",
+                codec
+            );
+            None
+        }
+        None => None,
+    };
+    chain.push(decoder_element.clone());
+
+    pipeline.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+    decoder_element.link(downstream)?;
+    if let (Some(tee_element), Some(encoded)) = (tee_element, encoded) {
+        tee_element
+            .link(encoded)
+            .context("failed to link the encoded stream")?;
+    }
+    for element in &chain {
+        element.sync_state_with_parent()?;
+    }
+
+    let jitterbuffer_sink_pad = rtpjitterbuffer_element.static_pad("sink").unwrap();
+    src_pad
+        .link(&jitterbuffer_sink_pad)
+        .context("failed to link rtspsrc pad")?;
+    tracing::info!("Successfully linked pads");
+
+    Ok(())
+}
+
+/// A local video file in any container and codec `uridecodebin` understands.
+pub struct VideoFileSource {
+    pub path: PathBuf,
+}
+
+impl FrameSource for VideoFileSource {
+    fn describe(&self) -> String {
+        format!("video file {}", self.path.display())
+    }
+
+    fn is_live(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        _encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()> {
+        let absolute_path = std::fs::canonicalize(&self.path)
+            .with_context(|| format!("failed to resolve {}", self.path.display()))?;
+        let uri = glib::filename_to_uri(&absolute_path, None)
+            .with_context(|| format!("failed to build URI for {}", absolute_path.display()))?;
+
+        let uridecodebin_element = gst::ElementFactory::make("uridecodebin")
+            .property("uri", uri.as_str())
+            .build()
+            .context("failed to create uridecodebin element")?;
+
+        pipeline.add(&uridecodebin_element)?;
+
+        link_video_pads_on_demand(&uridecodebin_element, downstream);
+
+        Ok(())
+    }
+}
+
+/// A directory of still images, fed into the pipeline through an `appsrc`.
+///
+/// Images are read in lexicographic order and stamped `frame_duration` apart.
+pub struct ImageDirectorySource {
+    pub directory: PathBuf,
+    pub images: Vec<PathBuf>,
+    pub frame_duration: gst::ClockTime,
+}
+
+impl ImageDirectorySource {
+    pub fn new(directory: &Path) -> anyhow::Result<Self> {
+        let mut images = std::fs::read_dir(directory)
+            .with_context(|| format!("failed to read directory {}", directory.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && image::ImageFormat::from_path(path).is_ok())
+            .collect::<Vec<_>>();
+        images.sort();
+
+        if images.is_empty() {
+            bail!("no images found in {}", directory.display());
+        }
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            images,
+            frame_duration: gst::ClockTime::SECOND,
+        })
+    }
+}
+
+impl FrameSource for ImageDirectorySource {
+    fn describe(&self) -> String {
+        format!(
+            "image directory {} ({} images)",
+            self.directory.display(),
+            self.images.len()
+        )
+    }
+
+    fn is_live(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        _encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()> {
+        let appsrc = gst_app::AppSrc::builder()
+            .name("imagesrc")
+            .format(gst::Format::Time)
+            .block(true)
+            .build();
+
+        let frame_duration = self.frame_duration;
+        let mut pending = self.images.clone().into_iter().enumerate();
+
+        appsrc.set_callbacks(
+            gst_app::AppSrcCallbacks::builder()
+                .need_data(move |appsrc, _| {
+                    for (index, path) in pending.by_ref() {
+                        let image = match image::open(&path) {
+                            Ok(image) => image.to_rgb8(),
+                            Err(err) => {
+                                tracing::warn!("Skipping {}: {}", path.display(), err);
+                                continue;
+                            }
+                        };
+
+                        let sample = match rgb_image_to_sample(
+                            &image,
+                            gst::ClockTime::from_nseconds(frame_duration.nseconds() * index as u64),
+                            frame_duration,
+                        ) {
+                            Ok(sample) => sample,
+                            Err(err) => {
+                                tracing::warn!("Skipping {}: {:?}", path.display(), err);
+                                continue;
+                            }
+                        };
+
+                        if let Err(err) = appsrc.push_sample(&sample) {
+                            tracing::debug!("appsrc refused sample: {:?}", err);
+                        }
+                        return;
+                    }
+
+                    let _ = appsrc.end_of_stream();
+                })
+                .build(),
+        );
+
+        let appsrc_element: gst::Element = appsrc.upcast();
+        pipeline.add(&appsrc_element)?;
+        appsrc_element.link(downstream)?;
+
+        Ok(())
+    }
+}
+
+/// Wrap a packed RGB image into a sample whose rows are padded to the stride
+/// GStreamer expects for `video/x-raw,format=RGB`.
+fn rgb_image_to_sample(
+    image: &image::RgbImage,
+    pts: gst::ClockTime,
+    duration: gst::ClockTime,
+) -> anyhow::Result<gst::Sample> {
+    let video_info =
+        gst_video::VideoInfo::builder(gst_video::VideoFormat::Rgb, image.width(), image.height())
+            .build()
+            .context("failed to build video info")?;
+
+    let stride = video_info.stride()[0] as usize;
+    let row_size = image.width() as usize * 3;
+    let mut data = vec![0u8; video_info.size()];
+    for (row, pixels) in image.as_raw().chunks_exact(row_size).enumerate() {
+        data[row * stride..row * stride + row_size].copy_from_slice(pixels);
+    }
+
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    {
+        let buffer = buffer.get_mut().unwrap();
+        buffer.set_pts(pts);
+        buffer.set_duration(duration);
+    }
+
+    let caps = video_info.to_caps().context("failed to build caps")?;
+    Ok(gst::Sample::builder().buffer(&buffer).caps(&caps).build())
+}
+
+/// A synthetic `videotestsrc`, useful to exercise the pipeline without a camera.
+pub struct TestSource {
+    pub pattern: Option<String>,
+}
+
+impl FrameSource for TestSource {
+    fn describe(&self) -> String {
+        match &self.pattern {
+            Some(pattern) => format!("test source ({pattern})"),
+            None => "test source".to_string(),
+        }
+    }
+
+    fn is_live(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        _encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()> {
+        let videotestsrc_element = gst::ElementFactory::make("videotestsrc")
+            .property("is-live", true)
+            .build()
+            .context("failed to create videotestsrc element")?;
+
+        if let Some(pattern) = &self.pattern {
+            let patterns = videotestsrc_element
+                .find_property("pattern")
+                .and_then(|pspec| pspec.downcast::<glib::ParamSpecEnum>().ok())
+                .map(|pspec| pspec.enum_class())
+                .context("videotestsrc has no pattern property")?;
+            if patterns.value_by_nick(pattern).is_none()
+                && patterns.value_by_name(pattern).is_none()
+            {
+                let nicks = patterns
+                    .values()
+                    .iter()
+                    .map(|value| value.nick())
+                    .collect::<Vec<_>>();
+                bail!(
+                    "unknown test pattern `{pattern}`, expected one of: {}",
+                    nicks.join(", ")
+                );
+            }
+            videotestsrc_element.set_property_from_str("pattern", pattern);
+        }
+
+        pipeline.add(&videotestsrc_element)?;
+        videotestsrc_element.link(downstream)?;
+
+        Ok(())
+    }
+}
+
+/// An arbitrary `gst-launch`-style description whose last element outputs
+/// raw video, e.g. `filesrc location=a.mkv ! matroskademux ! h264parse ! avdec_h264`.
+///
+/// Whether it is live is only known once its pipeline is paused.
+pub struct PipelineDescriptionSource {
+    pub description: String,
+}
+
+impl FrameSource for PipelineDescriptionSource {
+    fn describe(&self) -> String {
+        format!("pipeline `{}`", self.description)
+    }
+
+    fn is_live(&self) -> Option<bool> {
+        None
+    }
+
+    fn attach(
+        &self,
+        pipeline: &gst::Pipeline,
+        downstream: &gst::Element,
+        _encoded: Option<&gst::Element>,
+    ) -> anyhow::Result<()> {
+        let bin = gst::parse::bin_from_description(&self.description, true)
+            .context("failed to parse pipeline description")?;
+        let bin_element: gst::Element = bin.upcast();
+
+        pipeline.add(&bin_element)?;
+        bin_element
+            .link(downstream)
+            .context("pipeline description must end with a video output")?;
+
+        Ok(())
+    }
+}
+
+/// Link the first video pad exposed by a decodebin-like element to `downstream`.
+fn link_video_pads_on_demand(element: &gst::Element, downstream: &gst::Element) {
+    let downstream = downstream.clone();
+    element.connect_pad_added(move |_, src_pad| {
+        let caps = src_pad
+            .current_caps()
+            .unwrap_or_else(|| src_pad.query_caps(None));
+        let is_video = caps
+            .structure(0)
+            .is_some_and(|structure| structure.name().starts_with("video/"));
+        if !is_video {
+            return;
+        }
+
+        let sink_pad = downstream.static_pad("sink").unwrap();
+        if !sink_pad.is_linked() {
+            match src_pad.link(&sink_pad) {
+                Ok(_) => tracing::info!("Successfully linked pads"),
+                Err(err) => tracing::warn!("Failed to link pads: {:?}", err),
+            }
+        }
+    });
+}
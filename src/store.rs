@@ -0,0 +1,442 @@
+use crate::cli::QueryFormat;
+use crate::config::Config;
+use crate::event::{Event, LineCountEvent, TrackEvent, TrackEventKind, ZoneEvent};
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use rusqlite::{Connection, OpenFlags, params};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// SQLite database file, created if missing.
+    pub path: PathBuf,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("detections.sqlite3"),
+        }
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS detections (
+    id INTEGER PRIMARY KEY,
+    camera_id TEXT NOT NULL,
+    frame_id INTEGER NOT NULL,
+    pts_ms INTEGER,
+    timestamp TEXT NOT NULL,
+    model TEXT NOT NULL,
+    label TEXT NOT NULL,
+    confidence REAL NOT NULL,
+    x1 REAL NOT NULL,
+    y1 REAL NOT NULL,
+    x2 REAL NOT NULL,
+    y2 REAL NOT NULL,
+    track_id INTEGER,
+    crop_path TEXT
+);
+CREATE INDEX IF NOT EXISTS detections_camera_timestamp ON detections (camera_id, timestamp);
+CREATE INDEX IF NOT EXISTS detections_label_timestamp ON detections (label, timestamp);
+CREATE TABLE IF NOT EXISTS tracks (
+    camera_id TEXT NOT NULL,
+    track_id INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    ended_at TEXT,
+    frames INTEGER NOT NULL,
+    best_confidence REAL NOT NULL,
+    PRIMARY KEY (camera_id, track_id, started_at)
+);
+CREATE TABLE IF NOT EXISTS zone_events (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL,
+    camera_id TEXT NOT NULL,
+    zone TEXT NOT NULL,
+    track_id INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    dwell_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS zone_events_camera_zone_timestamp
+    ON zone_events (camera_id, zone, timestamp);
+CREATE TABLE IF NOT EXISTS line_counts (
+    camera_id TEXT NOT NULL,
+    line TEXT NOT NULL,
+    label TEXT NOT NULL,
+    interval_start TEXT NOT NULL,
+    interval_end TEXT NOT NULL,
+    count_in INTEGER NOT NULL,
+    count_out INTEGER NOT NULL,
+    PRIMARY KEY (camera_id, line, label, interval_start)
+);
+";
+
+/// One stored detection. Coordinates are normalized to the frame size.
+#[derive(Debug, Serialize)]
+pub struct StoredDetection {
+    pub camera_id: String,
+    pub frame_id: i64,
+    pub pts_ms: Option<i64>,
+    /// RFC 3339 in UTC with milliseconds, so it sorts chronologically.
+    pub timestamp: String,
+    pub model: String,
+    pub label: String,
+    pub confidence: f64,
+    pub x1: f64,
+    pub y1: f64,
+    pub x2: f64,
+    pub y2: f64,
+    pub track_id: Option<i64>,
+    pub crop_path: Option<String>,
+}
+
+impl StoredDetection {
+    pub const COLUMNS: &[&str] = &[
+        "camera_id",
+        "frame_id",
+        "pts_ms",
+        "timestamp",
+        "model",
+        "label",
+        "confidence",
+        "x1",
+        "y1",
+        "x2",
+        "y2",
+        "track_id",
+        "crop_path",
+    ];
+
+    /// The fields in the order of [`StoredDetection::COLUMNS`], as text.
+    pub fn values(&self) -> Vec<String> {
+        vec![
+            self.camera_id.clone(),
+            self.frame_id.to_string(),
+            self.pts_ms.map(|pts| pts.to_string()).unwrap_or_default(),
+            self.timestamp.clone(),
+            self.model.clone(),
+            self.label.clone(),
+            format!("{:.2}", self.confidence),
+            format!("{:.3}", self.x1),
+            format!("{:.3}", self.y1),
+            format!("{:.3}", self.x2),
+            format!("{:.3}", self.y2),
+            self.track_id.map(|id| id.to_string()).unwrap_or_default(),
+            self.crop_path.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+/// Filters of [`Store::query`]; unset filters match everything.
+#[derive(Debug)]
+pub struct Query {
+    pub camera_id: Option<String>,
+    pub label: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub min_confidence: Option<f32>,
+    pub limit: usize,
+}
+
+/// The detections database.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Open the database, creating it and its schema if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("failed to open database {}", path.display()))?;
+        // Lets `query` read while `run` writes
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .context("failed to enable write-ahead logging")?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("failed to create database schema")?;
+
+        Ok(Self { connection })
+    }
+
+    /// Open an existing database without writing to it.
+    pub fn open_read_only(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("failed to open database {}", path.display()))?;
+
+        Ok(Self { connection })
+    }
+
+    pub fn insert(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        let event = &inference.event;
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO detections
+                    (camera_id, frame_id, pts_ms, timestamp, model, label, confidence,
+                     x1, y1, x2, y2, track_id, crop_path)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?;
+            for object in &event.detections {
+                let bounding_box = &object.bounding_box;
+                statement.execute(params![
+                    event.camera_id,
+                    event.frame_id as i64,
+                    event.pts_ms.map(|pts| pts as i64),
+                    event.timestamp,
+                    event.model,
+                    object.label,
+                    object.confidence,
+                    bounding_box.x1,
+                    bounding_box.y1,
+                    bounding_box.x2,
+                    bounding_box.y2,
+                    object.track_id.map(|id| id as i64),
+                    object
+                        .crop_path
+                        .as_ref()
+                        .map(|path| path.to_string_lossy().into_owned()),
+                ])?;
+            }
+        }
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// Record a track when it starts and complete it when it ends.
+    pub fn record_track(&self, event: &TrackEvent) -> anyhow::Result<()> {
+        match event.kind {
+            TrackEventKind::Started => {
+                self.connection.execute(
+                    "INSERT OR REPLACE INTO tracks
+                        (camera_id, track_id, label, started_at, frames, best_confidence)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        event.camera_id,
+                        event.track_id as i64,
+                        event.label,
+                        event.timestamp,
+                        event.frames,
+                        event.best_confidence,
+                    ],
+                )?;
+            }
+            TrackEventKind::Ended => {
+                // Track IDs restart with the process, so end the latest one
+                self.connection.execute(
+                    "UPDATE tracks SET ended_at = ?3, frames = ?4, best_confidence = ?5
+                     WHERE rowid = (
+                         SELECT rowid FROM tracks
+                         WHERE camera_id = ?1 AND track_id = ?2 AND ended_at IS NULL
+                         ORDER BY started_at DESC LIMIT 1
+                     )",
+                    params![
+                        event.camera_id,
+                        event.track_id as i64,
+                        event.timestamp,
+                        event.frames,
+                        event.best_confidence,
+                    ],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record_zone_event(&self, event: &ZoneEvent) -> anyhow::Result<()> {
+        let kind = serde_json::to_value(event.kind)?;
+        self.connection.execute(
+            "INSERT INTO zone_events
+                (kind, camera_id, zone, track_id, label, timestamp, dwell_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                kind.as_str(),
+                event.camera_id,
+                event.zone,
+                event.track_id as i64,
+                event.label,
+                event.timestamp,
+                event.dwell_ms as i64,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Add the totals of an interval to those already stored, e.g. by a run
+    /// that stopped during the same interval.
+    pub fn record_line_count(&self, event: &LineCountEvent) -> anyhow::Result<()> {
+        self.connection.execute(
+            "INSERT INTO line_counts
+                (camera_id, line, label, interval_start, interval_end, count_in, count_out)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (camera_id, line, label, interval_start) DO UPDATE SET
+                count_in = count_in + excluded.count_in,
+                count_out = count_out + excluded.count_out",
+            params![
+                event.camera_id,
+                event.line,
+                event.label,
+                event.interval_start,
+                event.interval_end,
+                event.count_in as i64,
+                event.count_out as i64,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// The detections matching `query`, latest first.
+    pub fn query(&self, query: &Query) -> anyhow::Result<Vec<StoredDetection>> {
+        let timestamp = |time: SystemTime| humantime::format_rfc3339_millis(time).to_string();
+
+        let mut statement = self.connection.prepare(
+            "SELECT camera_id, frame_id, pts_ms, timestamp, model, label, confidence,
+                    x1, y1, x2, y2, track_id, crop_path
+             FROM detections
+             WHERE (?1 IS NULL OR camera_id = ?1)
+               AND (?2 IS NULL OR label = ?2)
+               AND (?3 IS NULL OR timestamp >= ?3)
+               AND (?4 IS NULL OR timestamp <= ?4)
+               AND (?5 IS NULL OR confidence >= ?5)
+             ORDER BY timestamp DESC, id DESC
+             LIMIT ?6",
+        )?;
+        let rows = statement.query_map(
+            params![
+                query.camera_id,
+                query.label,
+                query.since.map(timestamp),
+                query.until.map(timestamp),
+                query.min_confidence,
+                query.limit as i64,
+            ],
+            |row| {
+                Ok(StoredDetection {
+                    camera_id: row.get(0)?,
+                    frame_id: row.get(1)?,
+                    pts_ms: row.get(2)?,
+                    timestamp: row.get(3)?,
+                    model: row.get(4)?,
+                    label: row.get(5)?,
+                    confidence: row.get(6)?,
+                    x1: row.get(7)?,
+                    y1: row.get(8)?,
+                    x2: row.get(9)?,
+                    y2: row.get(10)?,
+                    track_id: row.get(11)?,
+                    crop_path: row.get(12)?,
+                })
+            },
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// Stores every detection in the SQLite database.
+pub struct DatabaseSink {
+    store: Store,
+}
+
+impl DatabaseSink {
+    pub fn open(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            store: Store::open(&config.database.path)?,
+        })
+    }
+}
+
+impl DetectionSink for DatabaseSink {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        if inference.event.detections.is_empty() {
+            return Ok(());
+        }
+
+        self.store.insert(inference)
+    }
+
+    fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        match event {
+            Event::Track(event) => self.store.record_track(event),
+            Event::Zone(event) => self.store.record_zone_event(event),
+            Event::LineCount(event) => self.store.record_line_count(event),
+            // Only the totals of every interval are kept
+            Event::LineCrossing(_) => Ok(()),
+            // Errors are for alerting, not history
+            Event::Error(_) => Ok(()),
+        }
+    }
+}
+
+/// Print `detections` to stdout in `format`.
+pub fn print(detections: &[StoredDetection], format: QueryFormat) -> anyhow::Result<()> {
+    match format {
+        QueryFormat::Table => {
+            let rows = detections
+                .iter()
+                .map(StoredDetection::values)
+                .collect::<Vec<_>>();
+            let widths = StoredDetection::COLUMNS
+                .iter()
+                .enumerate()
+                .map(|(column, name)| {
+                    rows.iter()
+                        .map(|row| row[column].len())
+                        .fold(name.len(), usize::max)
+                })
+                .collect::<Vec<_>>();
+
+            let print_row = |cells: &mut dyn Iterator<Item = &str>| {
+                let line = cells
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{cell:<width$}"))
+                    .collect::<Vec<_>>()
+                    .join("  ");
+                println!("{}", line.trim_end());
+            };
+
+            print_row(&mut StoredDetection::COLUMNS.iter().copied());
+            for row in &rows {
+                print_row(&mut row.iter().map(String::as_str));
+            }
+        }
+        QueryFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(detections)?);
+        }
+        QueryFormat::Csv => {
+            println!("{}", StoredDetection::COLUMNS.join(","));
+            for detection in detections {
+                let line = detection
+                    .values()
+                    .iter()
+                    .map(|value| csv_field(value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                println!("{line}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
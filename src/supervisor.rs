@@ -0,0 +1,447 @@
+use crate::pipeline;
+use crate::source::FrameSource;
+use anyhow::{Context, bail};
+use gst::prelude::*;
+use gstreamer as gst;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How often the bus is polled while checking for stalled streams.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a stopped pipeline may take to flush its last frames after EOS.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When and how fast a live source is rebuilt after it fails.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnection attempt.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after every failed attempt.
+    pub multiplier: f64,
+    /// Fraction of the delay (0.0 – 1.0) randomly added or removed.
+    pub jitter: f64,
+    /// Restart the pipeline when no frame arrived for this long.
+    #[serde(with = "humantime_serde")]
+    pub no_data_timeout: Duration,
+    /// Give up after this many consecutive failed attempts.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            no_data_timeout: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Exponential backoff with jitter.
+struct Backoff<'a> {
+    policy: &'a ReconnectPolicy,
+    attempt: u32,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(policy: &'a ReconnectPolicy) -> Self {
+        Self { policy, attempt: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The delay before the next attempt, or `None` if we should give up.
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max_attempts| self.attempt >= max_attempts)
+        {
+            return None;
+        }
+
+        let exponential = self.policy.initial_backoff.as_secs_f64()
+            * self.policy.multiplier.powi(self.attempt as i32);
+        let capped = exponential.min(self.policy.max_backoff.as_secs_f64());
+        let jitter = capped * self.policy.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+
+        self.attempt += 1;
+        Some(Duration::from_secs_f64((capped + jitter).max(0.0)))
+    }
+}
+
+/// Counts the frames reaching the appsink so the supervisor can tell a
+/// stalled stream from a healthy one.
+#[derive(Debug, Clone, Default)]
+pub struct Heartbeat(Arc<AtomicU64>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the pipeline of a session is live. Sources that cannot tell
+/// beforehand find out when the pipeline is paused, as live pipelines do not
+/// preroll; frames only reach the appsink once it plays, so its callbacks can
+/// rely on it.
+#[derive(Debug, Clone, Default)]
+pub struct Liveness(Arc<OnceLock<bool>>);
+
+impl Liveness {
+    pub fn is_live(&self) -> bool {
+        self.0.get().copied().unwrap_or(true)
+    }
+
+    /// Record whether the pipeline is live, unless it is already known.
+    fn set(&self, live: bool) {
+        let _ = self.0.set(live);
+    }
+}
+
+/// What a supervised camera is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CameraState {
+    /// Waiting for the first frame of a pipeline, or for the next attempt.
+    Connecting,
+    Playing,
+    /// The stream ended or the camera was stopped.
+    Stopped,
+    /// The camera gave up, e.g. after `reconnect.max_attempts`, or its
+    /// frames keep failing inference.
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub state: CameraState,
+    /// Why the camera is in the error state.
+    pub error: Option<String>,
+}
+
+/// Lets others follow and stop a supervised camera.
+#[derive(Clone)]
+pub struct Control(Arc<ControlInner>);
+
+struct ControlInner {
+    /// The state of the pipeline.
+    status: Mutex<Status>,
+    inference: Mutex<InferenceHealth>,
+    stopping: Mutex<bool>,
+    stopped: Condvar,
+}
+
+#[derive(Default)]
+struct InferenceHealth {
+    /// Frames in a row that failed inference.
+    failures: u32,
+    /// The last failure, once `failures` reached the threshold.
+    error: Option<String>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Self(Arc::new(ControlInner {
+            status: Mutex::new(Status {
+                state: CameraState::Connecting,
+                error: None,
+            }),
+            inference: Mutex::default(),
+            stopping: Mutex::new(false),
+            stopped: Condvar::new(),
+        }))
+    }
+}
+
+impl Control {
+    pub fn status(&self) -> Status {
+        let status = self.0.status.lock().unwrap().clone();
+        if self.has_ended() {
+            return status;
+        }
+
+        match &self.0.inference.lock().unwrap().error {
+            Some(error) => Status {
+                state: CameraState::Error,
+                error: Some(format!("inference keeps failing: {error}")),
+            },
+            None => status,
+        }
+    }
+
+    /// Whether the pipeline is over, for good or not.
+    pub fn has_ended(&self) -> bool {
+        matches!(
+            self.0.status.lock().unwrap().state,
+            CameraState::Stopped | CameraState::Error
+        )
+    }
+
+    pub fn set_state(&self, state: CameraState) {
+        *self.0.status.lock().unwrap() = Status { state, error: None };
+    }
+
+    pub fn fail(&self, error: String) {
+        *self.0.status.lock().unwrap() = Status {
+            state: CameraState::Error,
+            error: Some(error),
+        };
+    }
+
+    /// Count a frame that failed inference; from `threshold` failures in a
+    /// row on, the camera is in the error state. Returns the failures so far.
+    pub fn inference_failed(&self, error: String, threshold: u32) -> u32 {
+        let mut inference = self.0.inference.lock().unwrap();
+        inference.failures += 1;
+        if inference.failures >= threshold {
+            inference.error = Some(error);
+        }
+
+        inference.failures
+    }
+
+    /// Reset the failure count; returns whether the camera was in the error
+    /// state because of it.
+    pub fn inference_succeeded(&self) -> bool {
+        let mut inference = self.0.inference.lock().unwrap();
+        inference.failures = 0;
+
+        inference.error.take().is_some()
+    }
+
+    /// Ask the camera to stop: its pipeline is sent EOS, so that the frames
+    /// it holds still reach the appsinks.
+    pub fn stop(&self) {
+        *self.0.stopping.lock().unwrap() = true;
+        self.0.stopped.notify_all();
+    }
+
+    fn is_stopping(&self) -> bool {
+        *self.0.stopping.lock().unwrap()
+    }
+
+    /// Sleep for `duration` unless stopped in the meantime; returns whether
+    /// the camera should go on.
+    fn sleep(&self, duration: Duration) -> bool {
+        let stopping = self.0.stopping.lock().unwrap();
+        let (stopping, _) = self
+            .0
+            .stopped
+            .wait_timeout_while(stopping, duration, |stopping| !*stopping)
+            .unwrap();
+        !*stopping
+    }
+}
+
+/// Why a pipeline session ended.
+#[derive(Debug)]
+enum SessionEnd {
+    Eos,
+    Error(String),
+    Stalled(Duration),
+    Stopped,
+}
+
+/// Run `source` until it ends, rebuilding the pipeline after errors, EOS or
+/// a no-data timeout if it is live.
+///
+/// `make_callbacks` is called for every new pipeline, so whatever the
+/// callbacks feed (e.g. the inference queue) outlives reconnections. The
+/// state of the camera is kept up to date in `control`, which also stops it.
+pub fn supervise(
+    source: &dyn FrameSource,
+    accept_yuv: bool,
+    policy: &ReconnectPolicy,
+    control: &Control,
+    mut make_callbacks: impl FnMut(Heartbeat, Liveness) -> pipeline::Callbacks,
+) -> anyhow::Result<()> {
+    let mut backoff = Backoff::new(policy);
+
+    while !control.is_stopping() {
+        control.set_state(CameraState::Connecting);
+        let heartbeat = Heartbeat::default();
+        let liveness = Liveness::default();
+        let end = run_session(
+            source,
+            accept_yuv,
+            policy,
+            make_callbacks(heartbeat.clone(), liveness.clone()),
+            &heartbeat,
+            &liveness,
+            control,
+        );
+
+        if !liveness.is_live() {
+            return match end {
+                Ok(SessionEnd::Eos | SessionEnd::Stopped) => Ok(()),
+                Ok(SessionEnd::Error(err)) => bail!(err),
+                Ok(SessionEnd::Stalled(_)) => unreachable!("offline sources are not watched"),
+                Err(err) => Err(err),
+            };
+        }
+
+        match &end {
+            Ok(SessionEnd::Eos) => tracing::warn!("{} reached end of stream", source.describe()),
+            Ok(SessionEnd::Error(err)) => tracing::error!("{}", err),
+            Ok(SessionEnd::Stalled(elapsed)) => {
+                tracing::warn!("No frame from {} for {:?}", source.describe(), elapsed)
+            }
+            Ok(SessionEnd::Stopped) => break,
+            Err(err) => tracing::error!("Failed to run pipeline: {:?}", err),
+        }
+
+        // A session that delivered frames counts as a successful connection.
+        if heartbeat.count() > 0 {
+            backoff.reset();
+        }
+
+        let Some(delay) = backoff.next_delay() else {
+            bail!(
+                "giving up on {} after {} attempts",
+                source.describe(),
+                backoff.attempt
+            );
+        };
+        tracing::info!("Reconnecting to {} in {:?}", source.describe(), delay);
+        if !control.sleep(delay) {
+            break;
+        }
+    }
+
+    tracing::info!("Stopped reading {}", source.describe());
+    Ok(())
+}
+
+/// Build and play one pipeline until it ends, then shut it down.
+fn run_session(
+    source: &dyn FrameSource,
+    accept_yuv: bool,
+    policy: &ReconnectPolicy,
+    callbacks: pipeline::Callbacks,
+    heartbeat: &Heartbeat,
+    liveness: &Liveness,
+    control: &Control,
+) -> anyhow::Result<SessionEnd> {
+    if let Some(live) = source.is_live() {
+        liveness.set(live);
+    }
+    let pipeline = pipeline::build(source, accept_yuv, callbacks)?;
+
+    // Start the pipeline
+    let end = match play(&pipeline, liveness) {
+        Ok(()) => watch(
+            &pipeline,
+            liveness.is_live().then_some(policy.no_data_timeout),
+            heartbeat,
+            control,
+        ),
+        Err(err) => Ok(SessionEnd::Error(format!(
+            "failed to start pipeline: {err}"
+        ))),
+    };
+
+    // Shutdown pipeline
+    pipeline
+        .set_state(gst::State::Null)
+        .context("failed to stop pipeline")?;
+
+    end
+}
+
+/// Pause the pipeline to find out whether it is live, unless its source
+/// told already, then play it.
+fn play(pipeline: &gst::Pipeline, liveness: &Liveness) -> Result<(), gst::StateChangeError> {
+    let paused = pipeline.set_state(gst::State::Paused)?;
+    liveness.set(paused == gst::StateChangeSuccess::NoPreroll);
+
+    pipeline::set_live(pipeline, liveness.is_live());
+    pipeline.set_state(gst::State::Playing)?;
+    Ok(())
+}
+
+/// Wait until error, EOS, a stop or, if `no_data_timeout` is set, a stall.
+fn watch(
+    pipeline: &gst::Pipeline,
+    no_data_timeout: Option<Duration>,
+    heartbeat: &Heartbeat,
+    control: &Control,
+) -> anyhow::Result<SessionEnd> {
+    let bus = pipeline.bus().context("failed to get bus")?;
+
+    let mut last_count = heartbeat.count();
+    let mut last_progress = Instant::now();
+    let mut playing = false;
+    let mut stopped_at = None;
+
+    loop {
+        if let Some(msg) = bus.timed_pop(gst::ClockTime::from_mseconds(
+            POLL_INTERVAL.as_millis() as u64
+        )) {
+            match msg.view() {
+                gst::MessageView::Eos(..) if stopped_at.is_some() => {
+                    return Ok(SessionEnd::Stopped);
+                }
+                gst::MessageView::Eos(..) => return Ok(SessionEnd::Eos),
+                gst::MessageView::Error(err) if stopped_at.is_some() => {
+                    tracing::debug!("Error while stopping: {}", err.error());
+                    return Ok(SessionEnd::Stopped);
+                }
+                gst::MessageView::Error(err) => {
+                    return Ok(SessionEnd::Error(format!(
+                        "Error from {}: {}",
+                        err.src().map(|s| s.path_string()).unwrap_or("<?>".into()),
+                        err.error()
+                    )));
+                }
+                _ => (),
+            }
+        }
+
+        if control.is_stopping() {
+            match stopped_at {
+                None => {
+                    pipeline.send_event(gst::event::Eos::new());
+                    stopped_at = Some(Instant::now());
+                }
+                Some(stopped_at) if stopped_at.elapsed() >= STOP_TIMEOUT => {
+                    tracing::warn!("Pipeline did not drain within {:?}", STOP_TIMEOUT);
+                    return Ok(SessionEnd::Stopped);
+                }
+                Some(_) => (),
+            }
+            continue;
+        }
+
+        let count = heartbeat.count();
+        if count > 0 && !playing {
+            control.set_state(CameraState::Playing);
+            playing = true;
+        }
+        if count != last_count {
+            last_count = count;
+            last_progress = Instant::now();
+        } else if let Some(timeout) = no_data_timeout
+            && last_progress.elapsed() >= timeout
+        {
+            return Ok(SessionEnd::Stalled(last_progress.elapsed()));
+        }
+    }
+}
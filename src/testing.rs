@@ -0,0 +1,35 @@
+use crate::inference::{Detection, Frame};
+use image::DynamicImage;
+use std::sync::Arc;
+use std::time::Duration;
+use yolo_rs::BoundingBox;
+
+/// A 100×100 frame of the camera `cam`.
+pub fn frame(id: usize, pts: Duration) -> Frame {
+    Frame {
+        camera_id: Arc::from("cam"),
+        id,
+        pts: Some(pts),
+        image: DynamicImage::new_rgb8(100, 100),
+        live: false,
+    }
+}
+
+/// An untracked detection of `[x1, y1, x2, y2]`, in pixels.
+pub fn detection(label: &str, [x1, y1, x2, y2]: [f32; 4]) -> Detection {
+    Detection {
+        label: label.to_string(),
+        confidence: 0.9,
+        bounding_box: BoundingBox { x1, y1, x2, y2 },
+        track_id: None,
+    }
+}
+
+/// The person of track 1 standing at `(50, y)` of a [`frame`]: objects
+/// stand on the bottom center of their box.
+pub fn person_standing_at(y: f32) -> Detection {
+    Detection {
+        track_id: Some(1),
+        ..detection("person", [40.0, 10.0, 60.0, y])
+    }
+}
@@ -0,0 +1,569 @@
+use crate::event::{TrackEvent, TrackEventKind};
+use crate::inference::{Detection, Frame};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use yolo_rs::BoundingBox;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackingConfig {
+    pub enabled: bool,
+    /// Least overlap between a track's predicted box and a detection for
+    /// them to be associated.
+    pub iou_threshold: f32,
+    /// Detections at least this confident are matched first and may start
+    /// new tracks; weaker ones only keep existing tracks alive.
+    pub high_confidence: f32,
+    /// Number of matched frames before a track is reported.
+    pub min_hits: u32,
+    /// How long a track survives without being matched.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl Default for TrackingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            iou_threshold: 0.3,
+            high_confidence: 0.5,
+            min_hits: 2,
+            max_age: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The trackers of every camera, shared by the inference workers through
+/// [`Analysis::update`](crate::inference::Analysis::update).
+#[derive(Default)]
+pub struct Trackers {
+    cameras: Mutex<HashMap<String, Tracker>>,
+}
+
+impl Trackers {
+    /// Associate `detections` with the tracks of the frame's camera, filling
+    /// in their `track_id`, and return the tracks that started or ended.
+    pub fn update(
+        &self,
+        config: &TrackingConfig,
+        frame: &Frame,
+        detections: &mut [Detection],
+    ) -> Vec<TrackEvent> {
+        if !config.enabled {
+            return Vec::new();
+        }
+
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+        cameras
+            .entry(frame.camera_id.to_string())
+            .or_insert_with(|| Tracker::new(frame.camera_id.to_string()))
+            .update(config, frame, detections)
+    }
+
+    /// End the tracks of a camera and forget it, e.g. once it was removed.
+    pub fn remove(&self, camera_id: &str) -> Vec<TrackEvent> {
+        match self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(camera_id)
+        {
+            Some(mut tracker) => tracker.end_all(),
+            None => Vec::new(),
+        }
+    }
+
+    /// End every track that is still alive, e.g. once the streams stopped.
+    pub fn finish(&self) -> Vec<TrackEvent> {
+        self.cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values_mut()
+            .flat_map(|tracker| tracker.end_all())
+            .collect()
+    }
+}
+
+/// SORT/ByteTrack style tracker of one camera: boxes are predicted with a
+/// constant velocity Kalman filter and greedily matched on IoU.
+struct Tracker {
+    camera_id: String,
+    tracks: Vec<Track>,
+    next_id: u64,
+    last_time: Option<Duration>,
+    started_at: Instant,
+}
+
+impl Tracker {
+    fn new(camera_id: String) -> Self {
+        Self {
+            camera_id,
+            tracks: Vec::new(),
+            next_id: 1,
+            last_time: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    fn update(
+        &mut self,
+        config: &TrackingConfig,
+        frame: &Frame,
+        detections: &mut [Detection],
+    ) -> Vec<TrackEvent> {
+        let mut events = Vec::new();
+        let now = frame.pts.unwrap_or_else(|| self.started_at.elapsed());
+        let elapsed = match self.last_time {
+            // The stream restarted, e.g. after a reconnection
+            Some(last) if now < last => {
+                events.extend(self.end_all());
+                Duration::ZERO
+            }
+            Some(last) => now - last,
+            None => Duration::ZERO,
+        };
+        self.last_time = Some(now);
+
+        for track in &mut self.tracks {
+            track.predict(elapsed.as_secs_f32());
+        }
+
+        // Confident detections first, then the weak ones for what is left
+        let mut matched_tracks = vec![false; self.tracks.len()];
+        let mut matched_detections = vec![false; detections.len()];
+        for high in [true, false] {
+            let candidates = (0..detections.len())
+                .filter(|&index| {
+                    !matched_detections[index]
+                        && (detections[index].confidence >= config.high_confidence) == high
+                })
+                .collect::<Vec<_>>();
+
+            for (track_index, detection_index) in greedy_match(
+                &self.tracks,
+                &matched_tracks,
+                detections,
+                &candidates,
+                config.iou_threshold,
+            ) {
+                matched_tracks[track_index] = true;
+                matched_detections[detection_index] = true;
+
+                let track = &mut self.tracks[track_index];
+                let detection = &mut detections[detection_index];
+                track.correct(&detection.bounding_box, detection.confidence, now);
+                if !track.confirmed && track.hits >= config.min_hits {
+                    track.confirmed = true;
+                    events.push(track.event(&self.camera_id, TrackEventKind::Started));
+                }
+                if track.confirmed {
+                    detection.track_id = Some(track.id);
+                }
+            }
+        }
+
+        for (index, detection) in detections.iter_mut().enumerate() {
+            if matched_detections[index] || detection.confidence < config.high_confidence {
+                continue;
+            }
+
+            let mut track = Track::new(self.next_id, detection, now);
+            self.next_id += 1;
+            if track.hits >= config.min_hits {
+                track.confirmed = true;
+                detection.track_id = Some(track.id);
+                events.push(track.event(&self.camera_id, TrackEventKind::Started));
+            }
+            self.tracks.push(track);
+        }
+
+        let camera_id = &self.camera_id;
+        self.tracks.retain(|track| {
+            let expired = now.saturating_sub(track.last_seen) > config.max_age;
+            if expired && track.confirmed {
+                events.push(track.event(camera_id, TrackEventKind::Ended));
+            }
+            !expired
+        });
+
+        events
+    }
+
+    fn end_all(&mut self) -> Vec<TrackEvent> {
+        let camera_id = &self.camera_id;
+        self.tracks
+            .drain(..)
+            .filter(|track| track.confirmed)
+            .map(|track| track.event(camera_id, TrackEventKind::Ended))
+            .collect()
+    }
+}
+
+/// Pair tracks and candidate detections of the same label, best overlap
+/// first.
+fn greedy_match(
+    tracks: &[Track],
+    matched_tracks: &[bool],
+    detections: &[Detection],
+    candidates: &[usize],
+    iou_threshold: f32,
+) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (track_index, track) in tracks.iter().enumerate() {
+        if matched_tracks[track_index] {
+            continue;
+        }
+        let predicted = track.bounding_box();
+        for &detection_index in candidates {
+            let detection = &detections[detection_index];
+            if detection.label != track.label {
+                continue;
+            }
+            let overlap = iou(&predicted, &detection.bounding_box);
+            if overlap >= iou_threshold {
+                pairs.push((overlap, track_index, detection_index));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut used_tracks = vec![false; tracks.len()];
+    let mut used_detections = vec![false; detections.len()];
+    let mut matches = Vec::new();
+    for (_, track_index, detection_index) in pairs {
+        if used_tracks[track_index] || used_detections[detection_index] {
+            continue;
+        }
+        used_tracks[track_index] = true;
+        used_detections[detection_index] = true;
+        matches.push((track_index, detection_index));
+    }
+
+    matches
+}
+
+/// Intersection over union of two boxes.
+pub fn iou(a: &BoundingBox, b: &BoundingBox) -> f32 {
+    let width = (a.x2.min(b.x2) - a.x1.max(b.x1)).max(0.0);
+    let height = (a.y2.min(b.y2) - a.y1.max(b.y1)).max(0.0);
+    let intersection = width * height;
+    let union = (a.x2 - a.x1) * (a.y2 - a.y1) + (b.x2 - b.x1) * (b.y2 - b.y1) - intersection;
+
+    if union > 0.0 {
+        intersection / union
+    } else {
+        0.0
+    }
+}
+
+struct Track {
+    id: u64,
+    label: String,
+    /// Center x, center y, width and height.
+    filters: [KalmanFilter; 4],
+    hits: u32,
+    confirmed: bool,
+    best_confidence: f32,
+    first_seen: Duration,
+    last_seen: Duration,
+}
+
+impl Track {
+    fn new(id: u64, detection: &Detection, now: Duration) -> Self {
+        let [center_x, center_y, width, height] = box_to_measurement(&detection.bounding_box);
+        let size = width.max(height);
+
+        Self {
+            id,
+            label: detection.label.clone(),
+            filters: [center_x, center_y, width, height]
+                .map(|value| KalmanFilter::new(value, size)),
+            hits: 1,
+            confirmed: false,
+            best_confidence: detection.confidence,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+
+    fn predict(&mut self, dt: f32) {
+        let size = self.size();
+        for filter in &mut self.filters {
+            filter.predict(dt, size);
+        }
+    }
+
+    fn correct(&mut self, bounding_box: &BoundingBox, confidence: f32, now: Duration) {
+        let size = self.size();
+        for (filter, value) in self
+            .filters
+            .iter_mut()
+            .zip(box_to_measurement(bounding_box))
+        {
+            filter.correct(value, size);
+        }
+
+        self.hits += 1;
+        self.best_confidence = self.best_confidence.max(confidence);
+        self.last_seen = now;
+    }
+
+    fn size(&self) -> f32 {
+        self.filters[2]
+            .position
+            .max(self.filters[3].position)
+            .max(1.0)
+    }
+
+    fn bounding_box(&self) -> BoundingBox {
+        let [center_x, center_y, width, height] = self.filters.each_ref().map(|f| f.position);
+
+        BoundingBox {
+            x1: center_x - width / 2.0,
+            y1: center_y - height / 2.0,
+            x2: center_x + width / 2.0,
+            y2: center_y + height / 2.0,
+        }
+    }
+
+    fn event(&self, camera_id: &str, kind: TrackEventKind) -> TrackEvent {
+        TrackEvent::new(
+            kind,
+            camera_id,
+            self.id,
+            &self.label,
+            self.first_seen,
+            self.last_seen,
+            self.hits,
+            self.best_confidence,
+        )
+    }
+}
+
+fn box_to_measurement(bounding_box: &BoundingBox) -> [f32; 4] {
+    let BoundingBox { x1, y1, x2, y2 } = *bounding_box;
+
+    [(x1 + x2) / 2.0, (y1 + y2) / 2.0, x2 - x1, y2 - y1]
+}
+
+/// A constant velocity Kalman filter over one coordinate, with noise
+/// proportional to the size of the box like in ByteTrack.
+struct KalmanFilter {
+    position: f32,
+    velocity: f32,
+    /// Covariance `[[pp, pv], [pv, vv]]`.
+    pp: f32,
+    pv: f32,
+    vv: f32,
+}
+
+impl KalmanFilter {
+    /// Standard deviation of a measurement, relative to the box size.
+    const MEASUREMENT_NOISE: f32 = 1.0 / 20.0;
+    /// Standard deviation of the acceleration per second, relative to the
+    /// box size.
+    const PROCESS_NOISE: f32 = 1.0 / 10.0;
+
+    fn new(position: f32, size: f32) -> Self {
+        let position_std = 2.0 * Self::MEASUREMENT_NOISE * size;
+        let velocity_std = 10.0 * Self::PROCESS_NOISE * size;
+
+        Self {
+            position,
+            velocity: 0.0,
+            pp: position_std * position_std,
+            pv: 0.0,
+            vv: velocity_std * velocity_std,
+        }
+    }
+
+    fn predict(&mut self, dt: f32, size: f32) {
+        let q = (Self::PROCESS_NOISE * size).powi(2);
+
+        self.position += self.velocity * dt;
+        // P = F P Fᵀ + Q for F = [[1, dt], [0, 1]] and a white acceleration
+        self.pp += dt * (2.0 * self.pv + dt * self.vv) + q * dt.powi(3) / 3.0;
+        self.pv += dt * self.vv + q * dt.powi(2) / 2.0;
+        self.vv += q * dt;
+    }
+
+    fn correct(&mut self, measurement: f32, size: f32) {
+        let r = (Self::MEASUREMENT_NOISE * size).powi(2);
+        let s = self.pp + r;
+        let (k_position, k_velocity) = (self.pp / s, self.pv / s);
+        let residual = measurement - self.position;
+
+        self.position += k_position * residual;
+        self.velocity += k_velocity * residual;
+
+        let (pp, pv, vv) = (self.pp, self.pv, self.vv);
+        self.pp = (1.0 - k_position) * pp;
+        self.pv = (1.0 - k_position) * pv;
+        self.vv = vv - k_velocity * pv;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{detection, frame};
+
+    #[test]
+    fn kalman_filter_learns_a_constant_velocity() {
+        let mut filter = KalmanFilter::new(0.0, 100.0);
+        for step in 1..=50 {
+            filter.predict(0.1, 100.0);
+            filter.correct(10.0 * step as f32, 100.0);
+        }
+
+        assert!((filter.velocity - 100.0).abs() < 1.0, "{}", filter.velocity);
+        assert!((filter.position - 500.0).abs() < 1.0, "{}", filter.position);
+    }
+
+    #[test]
+    fn kalman_filter_weighs_measurements_by_its_uncertainty() {
+        let mut filter = KalmanFilter::new(0.0, 100.0);
+        let uncertainty = filter.pp;
+        filter.correct(50.0, 100.0);
+
+        // The new filter trusts the measurement more than its own guess
+        assert!(filter.position > 25.0 && filter.position < 50.0);
+        assert!(filter.pp < uncertainty);
+    }
+
+    #[test]
+    fn kalman_filter_predicts_along_its_velocity() {
+        let mut filter = KalmanFilter::new(0.0, 100.0);
+        filter.velocity = 20.0;
+        let uncertainty = filter.pp;
+        filter.predict(0.5, 100.0);
+
+        assert!((filter.position - 10.0).abs() < 1e-4);
+        assert!(filter.pp > uncertainty);
+    }
+
+    #[test]
+    fn iou_of_identical_disjoint_and_overlapping_boxes() {
+        let a = BoundingBox {
+            x1: 0.0,
+            y1: 0.0,
+            x2: 10.0,
+            y2: 10.0,
+        };
+        let b = BoundingBox {
+            x1: 5.0,
+            y1: 0.0,
+            x2: 15.0,
+            y2: 10.0,
+        };
+        let c = BoundingBox {
+            x1: 20.0,
+            y1: 20.0,
+            x2: 30.0,
+            y2: 30.0,
+        };
+
+        assert_eq!(iou(&a, &a), 1.0);
+        assert!((iou(&a, &b) - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(iou(&a, &c), 0.0);
+    }
+
+    #[test]
+    fn greedy_match_pairs_the_best_overlaps_first() {
+        let tracks = [
+            Track::new(
+                1,
+                &detection("person", [0.0, 0.0, 10.0, 10.0]),
+                Duration::ZERO,
+            ),
+            Track::new(
+                2,
+                &detection("person", [2.0, 0.0, 12.0, 10.0]),
+                Duration::ZERO,
+            ),
+        ];
+        // Overlaps the first track enough, but the second one perfectly
+        let detections = [detection("person", [2.0, 0.0, 12.0, 10.0])];
+
+        let matches = greedy_match(&tracks, &[false, false], &detections, &[0], 0.3);
+
+        assert_eq!(matches, [(1, 0)]);
+    }
+
+    #[test]
+    fn greedy_match_needs_the_same_label_and_enough_overlap() {
+        let tracks = [Track::new(
+            1,
+            &detection("person", [0.0, 0.0, 10.0, 10.0]),
+            Duration::ZERO,
+        )];
+        let detections = [
+            detection("car", [0.0, 0.0, 10.0, 10.0]),
+            detection("person", [8.0, 0.0, 18.0, 10.0]),
+        ];
+
+        let matches = greedy_match(&tracks, &[false], &detections, &[0, 1], 0.3);
+
+        assert!(matches.is_empty());
+    }
+
+    #[test]
+    fn greedy_match_skips_matched_tracks_and_other_candidates() {
+        let tracks = [
+            Track::new(
+                1,
+                &detection("person", [0.0, 0.0, 10.0, 10.0]),
+                Duration::ZERO,
+            ),
+            Track::new(
+                2,
+                &detection("person", [50.0, 0.0, 60.0, 10.0]),
+                Duration::ZERO,
+            ),
+        ];
+        let detections = [
+            detection("person", [0.0, 0.0, 10.0, 10.0]),
+            detection("person", [50.0, 0.0, 60.0, 10.0]),
+        ];
+
+        let matches = greedy_match(&tracks, &[true, false], &detections, &[0, 1], 0.3);
+        assert_eq!(matches, [(1, 1)]);
+
+        let matches = greedy_match(&tracks, &[false, false], &detections, &[1], 0.3);
+        assert_eq!(matches, [(1, 1)]);
+    }
+
+    #[test]
+    fn tracks_keep_their_id_until_they_expire() {
+        let config = TrackingConfig::default();
+        let mut tracker = Tracker::new("cam".to_string());
+
+        let mut ids = Vec::new();
+        let mut started = 0;
+        for step in 0..5 {
+            let x = step as f32;
+            let mut detections = [detection("person", [x, 0.0, x + 20.0, 40.0])];
+            let events = tracker.update(
+                &config,
+                &frame(step, Duration::from_millis(100 * step as u64)),
+                &mut detections,
+            );
+            started += events
+                .iter()
+                .filter(|event| event.kind == TrackEventKind::Started)
+                .count();
+            ids.push(detections[0].track_id);
+        }
+
+        // Reported from the `min_hits`-th frame on, always as the same track
+        assert_eq!(started, 1);
+        assert_eq!(ids, [None, Some(1), Some(1), Some(1), Some(1)]);
+
+        let events = tracker.update(&config, &frame(5, config.max_age * 2), &mut []);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, TrackEventKind::Ended);
+        assert_eq!(events[0].track_id, 1);
+    }
+}
@@ -0,0 +1,450 @@
+use crate::config::Config;
+use crate::event::{Event, Record};
+use crate::sink::{DetectionSink, Inference};
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Header carrying the hex HMAC-SHA256 of the request body, GitHub style:
+/// `sha256=<hex>`.
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Every batch is POSTed to each of these URLs.
+    pub urls: Vec<String>,
+    /// Only detections with these labels are sent; all labels when empty.
+    pub labels: Vec<String>,
+    /// Most events in one request.
+    pub batch_size: usize,
+    /// Longest time an event waits for its batch to fill up.
+    #[serde(with = "humantime_serde")]
+    pub batch_window: Duration,
+    /// Key used to sign the request bodies; unsigned when unset.
+    pub secret: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Retries of a batch kept in memory, without `spool_directory`, before
+    /// it is dropped.
+    pub max_retries: u32,
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Directory keeping the batches that could not be delivered, retried
+    /// until the receiver is back. They are kept in memory and retried at
+    /// most `max_retries` times when unset.
+    pub spool_directory: Option<PathBuf>,
+    /// Most batches kept in the spool; newer ones are dropped beyond that.
+    pub spool_limit: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            labels: Vec::new(),
+            batch_size: 20,
+            batch_window: Duration::from_secs(2),
+            secret: None,
+            timeout: Duration::from_secs(10),
+            max_retries: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            spool_directory: None,
+            spool_limit: 10_000,
+        }
+    }
+}
+
+impl WebhookConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// A request body that could not be delivered, as stored in the spool.
+#[derive(Serialize, Deserialize)]
+struct SpooledBatch {
+    url: String,
+    body: String,
+}
+
+/// A batch waiting in the spool for its URL to be retried.
+struct Spooled {
+    url: String,
+    body: SpooledBody,
+    /// Failed deliveries since it was spooled.
+    retries: u32,
+}
+
+enum SpooledBody {
+    /// In this file of the spool directory, as a [`SpooledBatch`].
+    File(PathBuf),
+    /// Without a spool directory.
+    Memory(String),
+}
+
+/// A URL whose receiver is down: its new batches go straight to the spool
+/// instead of being retried, until its spooled batches can be delivered.
+struct Spooling {
+    retry_at: Instant,
+    attempt: u32,
+}
+
+/// POSTs batches of detection and track events as a JSON array to the
+/// configured URLs.
+///
+/// A batch is posted once from the sink thread; if that fails, it is spooled
+/// and retried with backoff from [`DetectionSink::idle`], so that a receiver
+/// being down only holds up the sink for one request timeout at a time.
+pub struct WebhookSink {
+    config: Arc<Config>,
+    agent: ureq::Agent,
+    batch: Vec<serde_json::Value>,
+    batch_started_at: Option<Instant>,
+    /// The spooled batches, oldest first.
+    spool: VecDeque<Spooled>,
+    /// By URL, so that one receiver being down does not hold back the others.
+    spooling: HashMap<String, Spooling>,
+    spool_sequence: u64,
+}
+
+impl WebhookSink {
+    pub fn new(config: Arc<Config>) -> anyhow::Result<Self> {
+        if let Some(spool_directory) = &config.webhook.spool_directory {
+            std::fs::create_dir_all(spool_directory).with_context(|| {
+                format!(
+                    "failed to create spool directory {}",
+                    spool_directory.display()
+                )
+            })?;
+        }
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(config.webhook.timeout)
+            .build();
+        let mut sink = Self {
+            config: config.clone(),
+            agent,
+            batch: Vec::new(),
+            batch_started_at: None,
+            spool: VecDeque::new(),
+            spooling: HashMap::new(),
+            spool_sequence: 0,
+        };
+        if let Some(spool_directory) = &config.webhook.spool_directory {
+            sink.load_spool(spool_directory)?;
+        }
+
+        Ok(sink)
+    }
+
+    /// Index the batches left over from the last run, to deliver them first.
+    /// Those of URLs no longer configured are left alone.
+    fn load_spool(&mut self, spool_directory: &Path) -> anyhow::Result<()> {
+        let mut orphans = HashMap::<String, usize>::new();
+        for path in spooled_files(spool_directory)? {
+            let batch = match read_spooled(&path) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    tracing::warn!("Discarding {:#}", err);
+                    std::fs::remove_file(&path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                    continue;
+                }
+            };
+            if !self.config.webhook.urls.contains(&batch.url) {
+                *orphans.entry(batch.url).or_default() += 1;
+                continue;
+            }
+
+            self.spooling.entry(batch.url.clone()).or_insert(Spooling {
+                retry_at: Instant::now(),
+                attempt: 0,
+            });
+            self.spool.push_back(Spooled {
+                url: batch.url,
+                body: SpooledBody::File(path),
+                retries: 0,
+            });
+        }
+
+        for (url, count) in orphans {
+            tracing::warn!(
+                "Leaving {} spooled batches for {} in {}: it is no longer in webhook.urls",
+                count,
+                url,
+                spool_directory.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn push(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.batch
+            .push(serde_json::to_value(record).context("failed to serialize event")?);
+        self.batch_started_at.get_or_insert_with(Instant::now);
+
+        if self.batch.len() >= self.config.webhook.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.batch_started_at = None;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let body = serde_json::to_string(&self.batch).context("failed to serialize events")?;
+        self.batch.clear();
+
+        let config = self.config.clone();
+        for url in &config.webhook.urls {
+            // Behind the spooled batches, to keep them in order
+            if self.spooling.contains_key(url) {
+                self.spool(url, &body)?;
+                continue;
+            }
+
+            if let Err(err) = self.post(url, &body) {
+                let delay = config.webhook.backoff(0);
+                tracing::warn!(
+                    "Webhook {} failed, spooling its batches and retrying in {:?}: {:#}",
+                    url,
+                    delay,
+                    err
+                );
+                self.spooling.insert(
+                    url.clone(),
+                    Spooling {
+                        retry_at: Instant::now() + delay,
+                        attempt: 1,
+                    },
+                );
+                self.spool(url, &body)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn post(&self, url: &str, body: &str) -> anyhow::Result<()> {
+        let mut request = self.agent.post(url).set("Content-Type", "application/json");
+        if let Some(secret) = &self.config.webhook.secret {
+            request = request.set(SIGNATURE_HEADER, &sign(secret, body.as_bytes()));
+        }
+
+        request.send_string(body)?;
+        Ok(())
+    }
+
+    fn spool(&mut self, url: &str, body: &str) -> anyhow::Result<()> {
+        let webhook = &self.config.webhook;
+        if self.spool.len() >= webhook.spool_limit {
+            tracing::warn!("Webhook spool is full, dropping a batch for {}", url);
+            return Ok(());
+        }
+        let Some(spool_directory) = &webhook.spool_directory else {
+            self.spool.push_back(Spooled {
+                url: url.to_string(),
+                body: SpooledBody::Memory(body.to_string()),
+                retries: 0,
+            });
+            return Ok(());
+        };
+
+        // Named so that the files sort in the order they were spooled
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = spool_directory.join(format!(
+            "{:020}-{:06}.json",
+            since_epoch.as_millis(),
+            self.spool_sequence
+        ));
+        self.spool_sequence += 1;
+
+        let batch = SpooledBatch {
+            url: url.to_string(),
+            body: body.to_string(),
+        };
+        std::fs::write(&path, serde_json::to_vec(&batch)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        self.spool.push_back(Spooled {
+            url: batch.url,
+            body: SpooledBody::File(path),
+            retries: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Try to deliver the spooled batches of the URLs due for a retry in
+    /// order, skipping the rest of a URL's batches at its first failure.
+    fn retry_spool(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if !self
+            .spooling
+            .values()
+            .any(|spooling| spooling.retry_at <= now)
+        {
+            return Ok(());
+        }
+
+        let config = self.config.clone();
+        let webhook = &config.webhook;
+        let mut failed = HashSet::new();
+        let mut index = 0;
+        while index < self.spool.len() {
+            let url = self.spool[index].url.clone();
+            let due = self
+                .spooling
+                .get(&url)
+                .is_some_and(|spooling| spooling.retry_at <= now);
+            if !due || failed.contains(&url) {
+                index += 1;
+                continue;
+            }
+
+            let body = match &self.spool[index].body {
+                SpooledBody::Memory(body) => body.clone(),
+                SpooledBody::File(path) => match read_spooled(path) {
+                    Ok(batch) => batch.body,
+                    Err(err) => {
+                        tracing::warn!("Discarding {:#}", err);
+                        std::fs::remove_file(path)
+                            .with_context(|| format!("failed to remove {}", path.display()))?;
+                        self.spool.remove(index);
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(err) = self.post(&url, &body) {
+                let spooling = self.spooling.get_mut(&url).expect("due URLs are spooling");
+                let delay = webhook.backoff(spooling.attempt);
+                tracing::warn!(
+                    "Webhook {} is still failing, retrying its spool in {:?}: {:#}",
+                    url,
+                    delay,
+                    err
+                );
+                spooling.attempt += 1;
+                spooling.retry_at = Instant::now() + delay;
+                failed.insert(url);
+
+                let spooled = &mut self.spool[index];
+                spooled.retries += 1;
+                if matches!(spooled.body, SpooledBody::Memory(_))
+                    && spooled.retries > webhook.max_retries
+                {
+                    tracing::warn!(
+                        "Dropping a webhook batch for {} after {} retries",
+                        spooled.url,
+                        spooled.retries
+                    );
+                    self.spool.remove(index);
+                } else {
+                    index += 1;
+                }
+                continue;
+            }
+
+            if let SpooledBody::File(path) = &self.spool[index].body {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("failed to remove {}", path.display()))?;
+            }
+            self.spool.remove(index);
+        }
+
+        // The URLs that were due and did not fail again are delivered to directly
+        self.spooling
+            .retain(|url, spooling| spooling.retry_at > now || failed.contains(url));
+        Ok(())
+    }
+}
+
+impl DetectionSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn handle(&mut self, inference: &Inference) -> anyhow::Result<()> {
+        let labels = &self.config.webhook.labels;
+        let mut event = inference.event.clone();
+        if !labels.is_empty() {
+            event
+                .detections
+                .retain(|object| labels.contains(&object.label));
+        }
+
+        if !event.detections.is_empty() {
+            self.push(&Record::Detection(&event))?;
+        }
+        self.idle()
+    }
+
+    fn handle_event(&mut self, event: &Event) -> anyhow::Result<()> {
+        let labels = &self.config.webhook.labels;
+        if labels.is_empty() || labels.iter().any(|label| label == event.label()) {
+            self.push(&event.record())?;
+        }
+        self.idle()
+    }
+
+    fn idle(&mut self) -> anyhow::Result<()> {
+        if self
+            .batch_started_at
+            .is_some_and(|started_at| started_at.elapsed() >= self.config.webhook.batch_window)
+        {
+            self.flush()?;
+        }
+
+        self.retry_spool()
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+/// `sha256=<hex HMAC-SHA256 of body>`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn read_spooled(path: &Path) -> anyhow::Result<SpooledBatch> {
+    let content =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("unreadable {}", path.display()))
+}
+
+fn spooled_files(spool_directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(spool_directory)
+        .with_context(|| format!("failed to read {}", spool_directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|path| {
+        path.extension()
+            .is_some_and(|extension| extension == "json")
+    });
+    files.sort();
+
+    Ok(files)
+}
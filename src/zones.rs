@@ -0,0 +1,371 @@
+use crate::config::Config;
+use crate::event::{TrackEvent, TrackEventKind, ZoneEvent, ZoneEventKind};
+use crate::inference::{Detection, Frame};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// Used in events and topics; unique per camera.
+    pub name: String,
+    /// Corners of the zone as `[x, y]`, normalized to the frame size so that
+    /// `[0, 0]` is the top left corner and `[1, 1]` the bottom right one.
+    pub polygon: Vec<[f32; 2]>,
+    /// Only objects with these labels are followed; all labels when empty.
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Report a `zone_dwell` event this often while an object stays inside.
+    #[serde(default, with = "humantime_serde")]
+    pub dwell_interval: Option<Duration>,
+    /// Report a `loitering` event once an object stayed inside this long.
+    #[serde(default, with = "humantime_serde")]
+    pub loitering_threshold: Option<Duration>,
+}
+
+impl ZoneConfig {
+    fn follows(&self, label: &str) -> bool {
+        self.labels.is_empty() || self.labels.iter().any(|l| l == label)
+    }
+
+    /// Whether the point is inside the polygon, by ray casting.
+    fn contains(&self, [x, y]: [f32; 2]) -> bool {
+        let mut inside = false;
+        let mut previous = self.polygon.len() - 1;
+        for (index, &[x1, y1]) in self.polygon.iter().enumerate() {
+            let [x2, y2] = self.polygon[previous];
+            if (y1 > y) != (y2 > y) && x < (x2 - x1) * (y - y1) / (y2 - y1) + x1 {
+                inside = !inside;
+            }
+            previous = index;
+        }
+
+        inside
+    }
+}
+
+/// The objects inside the zones of every camera, shared by the inference
+/// workers through [`Analysis::update`](crate::inference::Analysis::update).
+#[derive(Default)]
+pub struct Zones {
+    cameras: Mutex<HashMap<String, CameraZones>>,
+}
+
+impl Zones {
+    /// Check the tracked `detections` of `frame` against the zones of its
+    /// camera and return what entered, stayed in or left them. Tracks that
+    /// ended in `track_events` leave the zones they were in.
+    pub fn update(
+        &self,
+        config: &Config,
+        frame: &Frame,
+        detections: &[Detection],
+        track_events: &[TrackEvent],
+    ) -> Vec<ZoneEvent> {
+        let Some(camera) = config
+            .cameras
+            .iter()
+            .find(|camera| *camera.id == *frame.camera_id)
+        else {
+            return Vec::new();
+        };
+        if camera.zones.is_empty() {
+            return Vec::new();
+        }
+
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = cameras
+            .entry(camera.id.clone())
+            .or_insert_with(CameraZones::new);
+
+        let mut events = state.end_tracks(&camera.id, &camera.zones, track_events);
+        events.extend(state.update(&camera.zones, frame, detections));
+        events
+    }
+
+    /// Let the tracks of a camera that ended leave their zones and forget
+    /// the camera, e.g. once it was removed.
+    pub fn remove(
+        &self,
+        config: &Config,
+        camera_id: &str,
+        track_events: &[TrackEvent],
+    ) -> Vec<ZoneEvent> {
+        let Some(mut state) = self
+            .cameras
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(camera_id)
+        else {
+            return Vec::new();
+        };
+
+        config
+            .cameras
+            .iter()
+            .find(|camera| camera.id == camera_id)
+            .map(|camera| state.end_tracks(&camera.id, &camera.zones, track_events))
+            .unwrap_or_default()
+    }
+
+    /// Let the tracks that ended leave their zones, e.g. once the streams
+    /// stopped.
+    pub fn end_tracks(&self, config: &Config, track_events: &[TrackEvent]) -> Vec<ZoneEvent> {
+        let mut cameras = self.cameras.lock().unwrap_or_else(PoisonError::into_inner);
+
+        config
+            .cameras
+            .iter()
+            .filter_map(|camera| Some((camera, cameras.get_mut(&camera.id)?)))
+            .flat_map(|(camera, state)| state.end_tracks(&camera.id, &camera.zones, track_events))
+            .collect()
+    }
+}
+
+/// A tracked object inside a zone.
+struct Occupant {
+    label: String,
+    entered_at: Duration,
+    last_inside: Duration,
+    last_dwell_report: Duration,
+    loitering_reported: bool,
+}
+
+struct CameraZones {
+    /// Keyed by zone index and track ID.
+    occupants: HashMap<(usize, u64), Occupant>,
+    started_at: Instant,
+}
+
+impl CameraZones {
+    fn new() -> Self {
+        Self {
+            occupants: HashMap::new(),
+            started_at: Instant::now(),
+        }
+    }
+
+    fn update(
+        &mut self,
+        zones: &[ZoneConfig],
+        frame: &Frame,
+        detections: &[Detection],
+    ) -> Vec<ZoneEvent> {
+        let now = frame.pts.unwrap_or_else(|| self.started_at.elapsed());
+        let (width, height) = (
+            frame.image.width().max(1) as f32,
+            frame.image.height().max(1) as f32,
+        );
+        let mut events = Vec::new();
+
+        for detection in detections {
+            // Only confirmed tracks have an ID; tentative ones may be noise
+            let Some(track_id) = detection.track_id else {
+                continue;
+            };
+            // Where the object stands rather than the middle of its box
+            let bounding_box = &detection.bounding_box;
+            let point = [
+                (bounding_box.x1 + bounding_box.x2) / 2.0 / width,
+                bounding_box.y2 / height,
+            ];
+
+            for (zone_index, zone) in zones.iter().enumerate() {
+                if !zone.follows(&detection.label) {
+                    continue;
+                }
+
+                let key = (zone_index, track_id);
+                let inside = zone.contains(point);
+                let event = |kind, dwell| {
+                    ZoneEvent::new(
+                        kind,
+                        &frame.camera_id,
+                        &zone.name,
+                        track_id,
+                        &detection.label,
+                        now,
+                        dwell,
+                    )
+                };
+
+                match self.occupants.get_mut(&key) {
+                    None if inside => {
+                        self.occupants.insert(
+                            key,
+                            Occupant {
+                                label: detection.label.clone(),
+                                entered_at: now,
+                                last_inside: now,
+                                last_dwell_report: now,
+                                loitering_reported: false,
+                            },
+                        );
+                        events.push(event(ZoneEventKind::ZoneEnter, Duration::ZERO));
+                    }
+                    None => (),
+                    Some(occupant) if inside => {
+                        occupant.last_inside = occupant.last_inside.max(now);
+                        let dwell = occupant.last_inside.saturating_sub(occupant.entered_at);
+
+                        if let Some(interval) = zone.dwell_interval
+                            && now.saturating_sub(occupant.last_dwell_report) >= interval
+                        {
+                            occupant.last_dwell_report = now;
+                            events.push(event(ZoneEventKind::ZoneDwell, dwell));
+                        }
+                        if let Some(threshold) = zone.loitering_threshold
+                            && !occupant.loitering_reported
+                            && dwell >= threshold
+                        {
+                            occupant.loitering_reported = true;
+                            events.push(event(ZoneEventKind::Loitering, dwell));
+                        }
+                    }
+                    Some(occupant) => {
+                        let dwell = occupant.last_inside.saturating_sub(occupant.entered_at);
+                        self.occupants.remove(&key);
+                        events.push(event(ZoneEventKind::ZoneExit, dwell));
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    fn end_tracks(
+        &mut self,
+        camera_id: &str,
+        zones: &[ZoneConfig],
+        track_events: &[TrackEvent],
+    ) -> Vec<ZoneEvent> {
+        let mut events = Vec::new();
+
+        for ended in track_events
+            .iter()
+            .filter(|event| event.kind == TrackEventKind::Ended && event.camera_id == camera_id)
+        {
+            for (zone_index, zone) in zones.iter().enumerate() {
+                let Some(occupant) = self.occupants.remove(&(zone_index, ended.track_id)) else {
+                    continue;
+                };
+                events.push(ZoneEvent::new(
+                    ZoneEventKind::ZoneExit,
+                    &ended.camera_id,
+                    &zone.name,
+                    ended.track_id,
+                    &occupant.label,
+                    occupant.last_inside,
+                    occupant.last_inside.saturating_sub(occupant.entered_at),
+                ));
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame, person_standing_at};
+
+    fn zone(polygon: &[[f32; 2]]) -> ZoneConfig {
+        ZoneConfig {
+            name: "zone".to_string(),
+            polygon: polygon.to_vec(),
+            labels: Vec::new(),
+            dwell_interval: None,
+            loitering_threshold: None,
+        }
+    }
+
+    fn square(x: f32, y: f32) -> ZoneConfig {
+        zone(&[[x, y], [x + 1.0, y], [x + 1.0, y + 1.0], [x, y + 1.0]])
+    }
+
+    #[test]
+    fn contains_points_inside_a_polygon() {
+        let triangle = zone(&[[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]]);
+
+        assert!(triangle.contains([0.5, 0.5]));
+        assert!(triangle.contains([0.5, 0.1]));
+        assert!(!triangle.contains([0.9, 0.9]));
+        assert!(!triangle.contains([0.1, 0.9]));
+        assert!(!triangle.contains([1.5, 0.5]));
+    }
+
+    #[test]
+    fn contains_follows_concave_polygons() {
+        // A U open at the top
+        let u = zone(&[
+            [0.0, 0.0],
+            [0.3, 0.0],
+            [0.3, 0.7],
+            [0.7, 0.7],
+            [0.7, 0.0],
+            [1.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 1.0],
+        ]);
+
+        assert!(u.contains([0.15, 0.3]));
+        assert!(!u.contains([0.5, 0.3]));
+        assert!(u.contains([0.5, 0.85]));
+    }
+
+    #[test]
+    fn contains_the_top_and_left_edges_only() {
+        let square = square(0.0, 0.0);
+
+        assert!(square.contains([0.5, 0.0]));
+        assert!(square.contains([0.0, 0.5]));
+        assert!(!square.contains([0.5, 1.0]));
+        assert!(!square.contains([1.0, 0.5]));
+
+        assert!(square.contains([0.0, 0.0]));
+        assert!(!square.contains([1.0, 0.0]));
+        assert!(!square.contains([1.0, 1.0]));
+        assert!(!square.contains([0.0, 1.0]));
+    }
+
+    #[test]
+    fn shared_edges_and_corners_belong_to_a_single_zone() {
+        let squares = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)].map(|(x, y)| square(x, y));
+        let owners = |point| squares.iter().filter(|zone| zone.contains(point)).count();
+
+        assert_eq!(owners([1.0, 1.0]), 1);
+        assert_eq!(owners([1.0, 0.5]), 1);
+        assert_eq!(owners([0.5, 1.0]), 1);
+    }
+
+    #[test]
+    fn tracks_enter_and_exit_zones() {
+        let zones = [zone(&[
+            [0.25, 0.25],
+            [0.75, 0.25],
+            [0.75, 0.75],
+            [0.25, 0.75],
+        ])];
+        let mut state = CameraZones::new();
+        let mut update = |pts, y| {
+            state
+                .update(
+                    &zones,
+                    &frame(0, Duration::from_secs(pts)),
+                    &[person_standing_at(y)],
+                )
+                .into_iter()
+                .map(|event| (event.kind, event.dwell_ms))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(update(0, 50.0), [(ZoneEventKind::ZoneEnter, 0)]);
+        assert!(update(1, 60.0).is_empty());
+        assert_eq!(update(2, 95.0), [(ZoneEventKind::ZoneExit, 1000)]);
+        assert!(update(3, 95.0).is_empty());
+    }
+}